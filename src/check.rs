@@ -0,0 +1,69 @@
+use crate::expr::compare;
+use crate::Data;
+use crate::Env;
+use crate::ErrorKind;
+use crate::Expr;
+use crate::ParseError;
+use crate::Scope;
+
+type CheckFn = Box<dyn Fn(&Scope, &Data) -> bool>;
+
+/// A constraint on the value of a pattern (see Pattern::Validate)
+pub enum Check {
+    Assert(CheckFn, String), // with the message to fail with
+    InRange(Data, Data),     // inclusive bounds
+    OneOf(Vec<Data>),
+    Equals(Expr),
+}
+
+impl Check {
+    pub(crate) fn check(&self, env: &Env, data: &Data) -> Result<(), ParseError> {
+        match self {
+            Check::Assert(f, message) => {
+                if f(env.scope, data) {
+                    Ok(())
+                } else {
+                    Err(invalid(message.clone()).with_actual(data.clone()))
+                }
+            }
+            Check::InRange(lo, hi) => {
+                let above = compare(data.clone(), lo.clone(), "in_range")?.is_ge();
+                let below = compare(data.clone(), hi.clone(), "in_range")?.is_le();
+                if above && below {
+                    Ok(())
+                } else {
+                    Err(
+                        invalid(format!("{} is not in the range {}..={}", data, lo, hi))
+                            .with_expected(vec![lo.clone(), hi.clone()])
+                            .with_actual(data.clone()),
+                    )
+                }
+            }
+            Check::OneOf(values) => {
+                if values.contains(data) {
+                    Ok(())
+                } else {
+                    Err(
+                        invalid(format!("{} is not one of the allowed values", data))
+                            .with_expected(values.clone())
+                            .with_actual(data.clone()),
+                    )
+                }
+            }
+            Check::Equals(expr) => {
+                let expected = expr.eval(env)?;
+                if *data == expected {
+                    Ok(())
+                } else {
+                    Err(invalid(format!("Expected {} but got {}", expected, data))
+                        .with_expected(expected)
+                        .with_actual(data.clone()))
+                }
+            }
+        }
+    }
+}
+
+fn invalid<S: Into<String>>(message: S) -> ParseError {
+    ParseError::new(ErrorKind::Validation, message)
+}
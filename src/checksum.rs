@@ -0,0 +1,150 @@
+/// Checksum algorithms (see Pattern::Checksum). Results are unsigned
+/// integers of the algorithm's width, e.g. 32 bits for Crc32
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Checksum {
+    Crc32,       // IEEE 802.3 (Ethernet, zlib, gzip, PNG)
+    Crc32c,      // Castagnoli (iSCSI, ext4, SCTP)
+    Crc16Arc,    // also known as CRC-16/IBM
+    Crc16Modbus, // like Crc16Arc, but starting from 0xFFFF
+    Crc16Ccitt,  // CRC-16/CCITT-FALSE (also known as CRC-16/IBM-3740)
+    Crc16Xmodem, // like Crc16Ccitt, but starting from 0
+    Crc16X25,    // HDLC and PPP frames
+    Adler32,     // zlib
+    Fletcher16,  // over bytes
+    Fletcher32,  // over little endian 16 bit words, the last one zero padded
+    Sum8,        // sum of the bytes, modulo 2^8
+    Sum16,       // sum of the bytes, modulo 2^16
+    Sum32,       // sum of the bytes, modulo 2^32
+    Xor8,        // all bytes xored together
+}
+
+/// Parameters of a CRC in the Rocksoft model. All the CRCs here have
+/// an all zero or all one init, which is the same reflected or not
+struct Crc {
+    width: u32,
+    poly: u64, // reflected, if reflect is set
+    init: u64,
+    reflect: bool,
+    xorout: u64,
+}
+
+impl Crc {
+    fn compute(&self, bytes: &[u8]) -> u64 {
+        let top = 1 << (self.width - 1);
+        let mask = (top << 1) - 1;
+        let mut crc = self.init;
+        for byte in bytes {
+            if self.reflect {
+                crc ^= *byte as u64;
+                for _ in 0..8 {
+                    crc = if crc & 1 != 0 {
+                        crc >> 1 ^ self.poly
+                    } else {
+                        crc >> 1
+                    };
+                }
+            } else {
+                crc ^= (*byte as u64) << (self.width - 8);
+                for _ in 0..8 {
+                    crc = if crc & top != 0 {
+                        crc << 1 ^ self.poly
+                    } else {
+                        crc << 1
+                    } & mask;
+                }
+            }
+        }
+        crc ^ self.xorout
+    }
+}
+
+impl Checksum {
+    pub(crate) fn compute(self, bytes: &[u8]) -> u64 {
+        let crc = |width, poly, init, reflect, xorout| {
+            Crc {
+                width,
+                poly,
+                init,
+                reflect,
+                xorout,
+            }
+            .compute(bytes)
+        };
+        match self {
+            Checksum::Crc32 => crc(32, 0xEDB88320, 0xFFFFFFFF, true, 0xFFFFFFFF),
+            Checksum::Crc32c => crc(32, 0x82F63B78, 0xFFFFFFFF, true, 0xFFFFFFFF),
+            Checksum::Crc16Arc => crc(16, 0xA001, 0, true, 0),
+            Checksum::Crc16Modbus => crc(16, 0xA001, 0xFFFF, true, 0),
+            Checksum::Crc16Ccitt => crc(16, 0x1021, 0xFFFF, false, 0),
+            Checksum::Crc16Xmodem => crc(16, 0x1021, 0, false, 0),
+            Checksum::Crc16X25 => crc(16, 0x8408, 0xFFFF, true, 0xFFFF),
+            Checksum::Adler32 => {
+                let (mut a, mut b) = (1, 0);
+                for byte in bytes {
+                    a = (a + *byte as u64) % 65521;
+                    b = (b + a) % 65521;
+                }
+                b << 16 | a
+            }
+            Checksum::Fletcher16 => {
+                let (mut a, mut b) = (0, 0);
+                for byte in bytes {
+                    a = (a + *byte as u64) % 255;
+                    b = (b + a) % 255;
+                }
+                b << 8 | a
+            }
+            Checksum::Fletcher32 => {
+                let (mut a, mut b) = (0, 0);
+                for word in bytes.chunks(2) {
+                    let word = word[0] as u64 | (*word.get(1).unwrap_or(&0) as u64) << 8;
+                    a = (a + word) % 65535;
+                    b = (b + a) % 65535;
+                }
+                b << 16 | a
+            }
+            Checksum::Sum8 | Checksum::Sum16 | Checksum::Sum32 => {
+                let sum = bytes.iter().fold(0u64, |sum, byte| sum + *byte as u64);
+                match self {
+                    Checksum::Sum8 => sum & 0xFF,
+                    Checksum::Sum16 => sum & 0xFFFF,
+                    _ => sum & 0xFFFFFFFF,
+                }
+            }
+            Checksum::Xor8 => bytes.iter().fold(0, |x, byte| x ^ *byte as u64),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Checksum;
+
+    #[test]
+    fn check_values() {
+        // the results for "123456789" that CRC catalogues list
+        let cases = [
+            (Checksum::Crc32, 0xCBF43926),
+            (Checksum::Crc32c, 0xE3069283),
+            (Checksum::Crc16Arc, 0xBB3D),
+            (Checksum::Crc16Modbus, 0x4B37),
+            (Checksum::Crc16Ccitt, 0x29B1),
+            (Checksum::Crc16Xmodem, 0x31C3),
+            (Checksum::Crc16X25, 0x906E),
+            (Checksum::Adler32, 0x091E01DE),
+            (Checksum::Sum8, 0xDD),
+            (Checksum::Sum16, 0x01DD),
+            (Checksum::Sum32, 0x01DD),
+            (Checksum::Xor8, 0x31),
+        ];
+        for (checksum, expected) in &cases {
+            assert_eq!(checksum.compute(b"123456789"), *expected, "{:?}", checksum);
+        }
+        assert_eq!(Checksum::Fletcher16.compute(b"abcde"), 0xC8F0);
+        assert_eq!(Checksum::Fletcher16.compute(b"abcdef"), 0x2057);
+        assert_eq!(Checksum::Fletcher32.compute(b"abcde"), 0xF04FC729);
+        assert_eq!(Checksum::Fletcher32.compute(b"abcdef"), 0x56502D2A);
+        assert_eq!(Checksum::Crc32.compute(b""), 0);
+        assert_eq!(Checksum::Adler32.compute(b""), 1);
+    }
+}
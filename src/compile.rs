@@ -0,0 +1,1140 @@
+use crate::prelude::*;
+use crate::Checksum;
+use crate::CompileError;
+use crate::Data;
+use crate::Encoding;
+use crate::Pattern;
+use crate::Unknown;
+use std::convert::TryFrom;
+use std::num::TryFromIntError;
+
+impl Pattern {
+    /// Builds a Pattern from a compact textual syntax, e.g.
+    ///
+    /// ```text
+    /// magic(42 4D) u32le:file_size u16 u16 u32le:pixel_offset [u64le; file_size]
+    /// ```
+    ///
+    /// Grammar:
+    ///
+    /// ```text
+    /// alts  := seq ('|' seq)*            any_of, if more than one
+    /// seq   := item*                     all_of, if not exactly one
+    /// item  := atom (':' name)*          store the value in the scope
+    /// atom  := type
+    ///        | 'magic' '(' bytes ')'
+    ///        | 'cstr' '(' encoding ')'
+    ///        | 'pascal_string' '(' alts enc? ')'  with alts giving the length
+    ///        | 'fixed_string' '(' expr (',' string)? enc? ')'  with padding
+    ///        | 'string_until' '(' bytes enc? ')'  up to a terminator
+    ///        | '(' alts ')'
+    ///        | '[' alts ';' count ']'    array_of or repeats
+    ///        | '{' (field (',' field)* ','?)? '}'
+    ///        | 'scope' '(' alts (';' name (',' name)*)? ')'
+    ///        | 'if' '(' expr ',' alts ',' alts ')'
+    ///        | 'switch' '(' expr ';' case (',' case)* ','? ')'
+    ///        | ('sized' | 'sized_exact') '(' expr ',' alts ')'
+    ///        | 'at' '(' expr ',' alts ')'  parse at an absolute offset
+    ///        | ('peek' | 'not') '(' alts ')'  lookahead, consuming nothing
+    ///        | 'in_range' '(' alts ';' literal ',' literal ')'  inclusive
+    ///        | 'one_of' '(' alts ';' literal (',' literal)* ')'
+    ///        | 'expect_eq' '(' alts ';' expr ')'
+    ///        | ('enumerate' | 'flags') '(' alts ';' name (',' name)* ')'
+    ///        | 'with_endian' '(' expr ',' alts ')'  byte order of the dyn types
+    ///        | 'byte_order' '(' bytes ';' bytes ')'  little, then big endian marker
+    ///        | 'checksum' '(' algorithm ';' expr ',' expr ';' alts ')'
+    ///          stored checksum of the bytes between two offsets
+    ///        | ('skip' | 'seek') '(' expr ')'
+    ///        | ('bits' | 'bits_lsb') '(' int ')'
+    ///        | 'bitfield' '(' alts ';' name '=' int (',' name '=' int)* ','? ')'
+    /// bytes := (hex-byte | string)*
+    /// enc   := ';' encoding               e.g. utf16le (see below)
+    /// case  := (literal | '_') '=>' alts     '_' is the default
+    /// name  := int '=' string             symbolic name of a value (or flag)
+    ///        | '_' '=' ('error' | 'keep' | 'tag')  for unknown values
+    /// count := expr                       array_of
+    ///        | '*' | '+'                  many, many1
+    ///        | '..'                       repeat_eof
+    ///        | ('until' | 'while') expr   repeat_until, repeat_while
+    /// field := name '=' alts              record field
+    /// expr  := literal | name | '(' expr ')' | '-' expr
+    ///        | expr binop expr
+    ///        | ('min' | 'max') '(' expr ',' expr ')'
+    ///        | 'if' '(' expr ',' expr ',' expr ')'
+    ///        | 'offset' '(' ')'           current position
+    ///        | 'len' '(' ')'              length of the whole input
+    /// ```
+    ///
+    /// where a literal is an integer or a "string".
+    /// Binary operators have the same precedence as in Rust:
+    /// `* / %`, then `+ -`, `<< >>`, `&`, `|`, and finally the
+    /// comparisons `== != < <= > >=`.
+    ///
+    /// where type is one of u8, i8, cstr, {u,i}{16,32,64,128}{,le,be,dyn},
+    /// f{32,64}{,le,be,dyn} or one of the varints uleb128 (or varint),
+    /// sleb128, zigzag, vlq and git_varint, or eof (only matching at
+    /// the end) or rest (all remaining bytes). Unsuffixed types are
+    /// little endian, like their prelude counterparts, and dyn types
+    /// take their byte order from the enclosing with_endian. Encodings
+    /// are utf8 (the default), utf16 (by byte order mark), utf16le,
+    /// utf16be, latin1, ascii and windows1252. Checksum algorithms are
+    /// crc32, crc32c, crc16_{arc,modbus,ccitt,xmodem,x25}, adler32,
+    /// fletcher{16,32}, sum{8,16,32} and xor8. '#' starts a comment
+    /// that runs to the end of the line.
+    pub fn compile(src: &str) -> Result<Pattern, CompileError> {
+        let mut compiler = Compiler {
+            chars: src.chars().collect(),
+            pos: 0,
+        };
+        let pattern = compiler.alts()?;
+        compiler.skip_space();
+        match compiler.peek() {
+            None => Ok(pattern),
+            Some(c) => Err(compiler.error(format!("Unexpected {:?}", c))),
+        }
+    }
+}
+
+fn primitive(name: &str) -> Option<Pattern> {
+    Some(match name {
+        "u8" => U8,
+        "i8" => I8,
+        "u16" | "u16le" => LE_U16,
+        "u32" | "u32le" => LE_U32,
+        "u64" | "u64le" => LE_U64,
+        "u16be" => BE_U16,
+        "u32be" => BE_U32,
+        "u64be" => BE_U64,
+        "u128" | "u128le" => LE_U128,
+        "u128be" => BE_U128,
+        "i16" | "i16le" => LE_I16,
+        "i32" | "i32le" => LE_I32,
+        "i64" | "i64le" => LE_I64,
+        "i16be" => BE_I16,
+        "i32be" => BE_I32,
+        "i64be" => BE_I64,
+        "i128" | "i128le" => LE_I128,
+        "i128be" => BE_I128,
+        "f32" | "f32le" => Pattern::LeF32,
+        "f64" | "f64le" => Pattern::LeF64,
+        "f32be" => Pattern::BeF32,
+        "f64be" => Pattern::BeF64,
+        "uleb128" | "varint" => ULEB128,
+        "sleb128" => SLEB128,
+        "zigzag" => ZIGZAG,
+        "vlq" => VLQ,
+        "git_varint" => GIT_VARINT,
+        "u16dyn" => u16(),
+        "u32dyn" => u32(),
+        "u64dyn" => u64(),
+        "u128dyn" => u128(),
+        "i16dyn" => i16(),
+        "i32dyn" => i32(),
+        "i64dyn" => i64(),
+        "i128dyn" => i128(),
+        "f32dyn" => f32(),
+        "f64dyn" => f64(),
+        "cstr" => CSTR,
+        "eof" => eof(),
+        "rest" => rest(),
+        _ => return None,
+    })
+}
+
+struct Compiler {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Compiler {
+    fn error<S: Into<String>>(&self, message: S) -> CompileError {
+        self.error_at(self.pos, message)
+    }
+    fn error_at<S: Into<String>>(&self, pos: usize, message: S) -> CompileError {
+        let mut line = 1;
+        let mut column = 1;
+        for c in &self.chars[..pos] {
+            if *c == '\n' {
+                line += 1;
+                column = 1;
+            } else {
+                column += 1;
+            }
+        }
+        CompileError {
+            message: message.into(),
+            line,
+            column,
+        }
+    }
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).cloned()
+    }
+    fn skip_space(&mut self) {
+        while let Some(c) = self.peek() {
+            if c == '#' {
+                while self.peek().map(|c| c != '\n').unwrap_or(false) {
+                    self.pos += 1;
+                }
+            } else if c.is_whitespace() {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Skips whitespace, then consumes c if it is next
+    fn consume(&mut self, c: char) -> bool {
+        self.skip_space();
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+    fn expect(&mut self, c: char) -> Result<(), CompileError> {
+        if self.consume(c) {
+            Ok(())
+        } else {
+            Err(self.error(format!("Expected {:?}", c)))
+        }
+    }
+    fn word(&mut self) -> String {
+        self.skip_space();
+        let start = self.pos;
+        while self
+            .peek()
+            .map(|c| c.is_ascii_alphanumeric() || c == '_')
+            .unwrap_or(false)
+        {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos].iter().collect()
+    }
+    fn name(&mut self) -> Result<String, CompileError> {
+        self.skip_space();
+        let start = self.pos;
+        let name = self.word();
+        if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+            Err(self.error_at(start, "Expected a name"))
+        } else {
+            Ok(name)
+        }
+    }
+
+    fn alts(&mut self) -> Result<Pattern, CompileError> {
+        let mut alts = vec![self.seq()?];
+        while self.consume('|') {
+            alts.push(self.seq()?);
+        }
+        Ok(if alts.len() == 1 {
+            alts.pop().unwrap()
+        } else {
+            any_of(alts)
+        })
+    }
+    fn seq(&mut self) -> Result<Pattern, CompileError> {
+        let mut items = Vec::new();
+        loop {
+            self.skip_space();
+            match self.peek() {
+                None | Some('|') | Some(')') | Some(']') | Some(';') | Some(',') | Some('}') => {
+                    break
+                }
+                _ => items.push(self.item()?),
+            }
+        }
+        Ok(if items.len() == 1 {
+            items.pop().unwrap()
+        } else {
+            all_of(items)
+        })
+    }
+    fn item(&mut self) -> Result<Pattern, CompileError> {
+        let mut pattern = self.atom()?;
+        while self.consume(':') {
+            pattern = pattern.store(self.name()?);
+        }
+        Ok(pattern)
+    }
+    fn atom(&mut self) -> Result<Pattern, CompileError> {
+        self.skip_space();
+        let start = self.pos;
+        if self.consume('(') {
+            let pattern = self.alts()?;
+            self.expect(')')?;
+            return Ok(pattern);
+        }
+        if self.consume('[') {
+            let pattern = self.alts()?;
+            self.expect(';')?;
+            let pattern = self.repetition(pattern)?;
+            self.expect(']')?;
+            return Ok(pattern);
+        }
+        if self.consume('{') {
+            let mut fields = Vec::new();
+            while !self.consume('}') {
+                let name = self.name()?;
+                self.expect('=')?;
+                fields.push((name, self.alts()?));
+                if !self.consume(',') {
+                    self.expect('}')?;
+                    break;
+                }
+            }
+            return Ok(record(fields));
+        }
+        let name = self.word();
+        if name == "magic" {
+            self.expect('(')?;
+            let bytes = self.bytes()?;
+            self.expect(')')?;
+            return Ok(magic(&bytes));
+        }
+        if name == "cstr" && self.consume('(') {
+            let pattern = CSTR.encoding(self.encoding_name()?);
+            self.expect(')')?;
+            return Ok(pattern);
+        }
+        if name == "pascal_string" {
+            self.expect('(')?;
+            let len = self.alts()?;
+            return self.encoding(pascal_string(len));
+        }
+        if name == "fixed_string" {
+            self.expect('(')?;
+            let len = self.expr()?;
+            let pattern = if self.consume(',') {
+                let padding = self.literal()?;
+                match padding.str() {
+                    Some(padding) => fixed_string(len).padding(padding.as_bytes()),
+                    None => return Err(self.error("Expected a string of padding characters")),
+                }
+            } else {
+                fixed_string(len)
+            };
+            return self.encoding(pattern);
+        }
+        if name == "string_until" {
+            self.expect('(')?;
+            let start = self.pos;
+            let terminator = self.bytes()?;
+            if terminator.is_empty() {
+                return Err(self.error_at(start, "Expected a terminator"));
+            }
+            return self.encoding(string_until(&terminator));
+        }
+        if name == "if" {
+            self.expect('(')?;
+            let cond = self.expr()?;
+            self.expect(',')?;
+            let then_ = self.alts()?;
+            self.expect(',')?;
+            let else_ = self.alts()?;
+            self.expect(')')?;
+            return Ok(if_else(cond, then_, else_));
+        }
+        if name == "switch" {
+            self.expect('(')?;
+            let expr = self.expr()?;
+            self.expect(';')?;
+            let mut cases = Vec::new();
+            let mut default = None;
+            loop {
+                self.skip_space();
+                let key_start = self.pos;
+                let key = if self.consume('_') {
+                    None
+                } else {
+                    Some(self.literal()?)
+                };
+                self.expect('=')?;
+                self.expect('>')?;
+                let pattern = self.alts()?;
+                match key {
+                    Some(key) => cases.push((key, pattern)),
+                    None if default.is_none() => default = Some(pattern),
+                    None => return Err(self.error_at(key_start, "Duplicate default case")),
+                }
+                if !self.consume(',') {
+                    self.expect(')')?;
+                    break;
+                }
+                if self.consume(')') {
+                    break;
+                }
+            }
+            return Ok(switch(expr, cases, default));
+        }
+        if name == "sized" || name == "sized_exact" {
+            self.expect('(')?;
+            let size = self.expr()?;
+            self.expect(',')?;
+            let pattern = self.alts()?;
+            self.expect(')')?;
+            return Ok(if name == "sized" {
+                sized(size, pattern)
+            } else {
+                sized_exact(size, pattern)
+            });
+        }
+        if name == "at" {
+            self.expect('(')?;
+            let offset = self.expr()?;
+            self.expect(',')?;
+            let pattern = self.alts()?;
+            self.expect(')')?;
+            return Ok(at(offset, pattern));
+        }
+        if name == "peek" || name == "not" {
+            self.expect('(')?;
+            let pattern = self.alts()?;
+            self.expect(')')?;
+            return Ok(if name == "peek" {
+                peek(pattern)
+            } else {
+                not(pattern)
+            });
+        }
+        if name == "in_range" || name == "one_of" || name == "expect_eq" {
+            self.expect('(')?;
+            let pattern = self.alts()?;
+            self.expect(';')?;
+            let pattern = match name.as_str() {
+                "in_range" => {
+                    let lo = self.literal()?;
+                    self.expect(',')?;
+                    pattern.in_range(lo, self.literal()?)
+                }
+                "one_of" => {
+                    let mut values = vec![self.literal()?];
+                    while self.consume(',') {
+                        values.push(self.literal()?);
+                    }
+                    pattern.one_of(values)
+                }
+                _ => pattern.expect_eq(self.expr()?),
+            };
+            self.expect(')')?;
+            return Ok(pattern);
+        }
+        if name == "enumerate" || name == "flags" {
+            self.expect('(')?;
+            let pattern = self.alts()?;
+            self.expect(';')?;
+            let mut names = Vec::new();
+            let mut unknown = Unknown::Keep;
+            loop {
+                self.skip_space();
+                let start = self.pos;
+                if self.consume('_') {
+                    self.expect('=')?;
+                    self.skip_space();
+                    let start = self.pos;
+                    unknown = match self.word().as_str() {
+                        "error" => Unknown::Error,
+                        "keep" => Unknown::Keep,
+                        "tag" => Unknown::Tag,
+                        _ => return Err(self.error_at(start, "Expected error, keep or tag")),
+                    };
+                } else {
+                    let value = self.literal()?;
+                    self.expect('=')?;
+                    match (value.i128(), self.literal()?.str()) {
+                        (Some(value), Some(name)) => names.push((value, name.to_string())),
+                        _ => return Err(self.error_at(start, "Expected int = \"name\"")),
+                    }
+                }
+                if !self.consume(',') {
+                    self.expect(')')?;
+                    break;
+                }
+            }
+            let out_of_range = |_| self.error("Value out of range");
+            return Ok(if name == "enumerate" {
+                let names = names
+                    .into_iter()
+                    .map(|(value, name)| Ok((i64::try_from(value)?, name)))
+                    .collect::<Result<Vec<_>, TryFromIntError>>()
+                    .map_err(out_of_range)?;
+                pattern.enumerate(names, unknown)
+            } else {
+                let flags = names
+                    .into_iter()
+                    .map(|(mask, name)| Ok((u64::try_from(mask)?, name)))
+                    .collect::<Result<Vec<_>, TryFromIntError>>()
+                    .map_err(out_of_range)?;
+                pattern.flags(flags, unknown)
+            });
+        }
+        if name == "with_endian" {
+            self.expect('(')?;
+            let endian = self.expr()?;
+            self.expect(',')?;
+            let pattern = self.alts()?;
+            self.expect(')')?;
+            return Ok(with_endian(endian, pattern));
+        }
+        if name == "byte_order" {
+            self.expect('(')?;
+            let little = self.bytes()?;
+            self.expect(';')?;
+            let big = self.bytes()?;
+            self.expect(')')?;
+            return Ok(byte_order(&little, &big));
+        }
+        if name == "checksum" {
+            self.expect('(')?;
+            let algorithm = self.checksum_name()?;
+            self.expect(';')?;
+            let start = self.expr()?;
+            self.expect(',')?;
+            let end = self.expr()?;
+            self.expect(';')?;
+            let stored = self.alts()?;
+            self.expect(')')?;
+            return Ok(checksum(algorithm, (start, end), stored));
+        }
+        if name == "skip" || name == "seek" {
+            self.expect('(')?;
+            let expr = self.expr()?;
+            self.expect(')')?;
+            return Ok(if name == "skip" {
+                skip(expr)
+            } else {
+                seek(expr)
+            });
+        }
+        if name == "bits" || name == "bits_lsb" {
+            self.expect('(')?;
+            let width = self.width(1)?;
+            self.expect(')')?;
+            return Ok(if name == "bits" {
+                bits(width)
+            } else {
+                bits_lsb(width)
+            });
+        }
+        if name == "bitfield" {
+            self.expect('(')?;
+            let pattern = self.alts()?;
+            self.expect(';')?;
+            let mut fields = Vec::new();
+            loop {
+                let name = self.name()?;
+                self.expect('=')?;
+                fields.push((name, self.width(0)?));
+                if !self.consume(',') {
+                    self.expect(')')?;
+                    break;
+                }
+                if self.consume(')') {
+                    break;
+                }
+            }
+            let total = fields.iter().map(|(_, width)| width).sum::<u32>();
+            if total > 64 {
+                return Err(self.error_at(start, "Bitfield is wider than 64 bits"));
+            }
+            if total > pattern.bit_width().unwrap_or(total) {
+                return Err(self.error_at(start, "Bitfield is wider than its integer"));
+            }
+            let fields: Vec<_> = fields
+                .iter()
+                .map(|(name, width)| (name.as_str(), *width))
+                .collect();
+            return Ok(bitfield(pattern, &fields));
+        }
+        if name == "scope" {
+            self.expect('(')?;
+            let pattern = self.alts()?;
+            let mut exports = Vec::new();
+            if self.consume(';') {
+                exports.push(self.name()?);
+                while self.consume(',') {
+                    exports.push(self.name()?);
+                }
+            }
+            self.expect(')')?;
+            return Ok(scoped_export(pattern, exports));
+        }
+        match primitive(&name) {
+            Some(pattern) => Ok(pattern),
+            None if name.is_empty() => match self.peek() {
+                Some(c) => Err(self.error_at(start, format!("Unexpected {:?}", c))),
+                None => Err(self.error_at(start, "Unexpected end of input")),
+            },
+            None => Err(self.error_at(start, format!("Unknown type {:?}", name))),
+        }
+    }
+    /// What comes after the ';' in '[' pattern ';' ... ']'
+    fn repetition(&mut self, pattern: Pattern) -> Result<Pattern, CompileError> {
+        if self.consume('*') {
+            return Ok(many(pattern));
+        }
+        if self.consume('+') {
+            return Ok(many1(pattern));
+        }
+        if self.consume('.') {
+            self.expect('.')?;
+            return Ok(repeat_eof(pattern));
+        }
+        let start = self.pos;
+        match self.word().as_str() {
+            "until" => Ok(repeat_until(pattern, self.expr()?)),
+            "while" => Ok(repeat_while(pattern, self.expr()?)),
+            _ => {
+                self.pos = start;
+                Ok(array_of(pattern, self.expr()?))
+            }
+        }
+    }
+    /// A number of bits, from min to 64
+    fn width(&mut self, min: u32) -> Result<u32, CompileError> {
+        self.skip_space();
+        let start = self.pos;
+        match self.literal()? {
+            Data::Int(i) if min as i64 <= i && i <= 64 => Ok(i as u32),
+            _ => Err(self.error_at(
+                start,
+                format!("Expected a number of bits from {} to 64", min),
+            )),
+        }
+    }
+    /// Hex bytes and strings, e.g. 0d 0a "--", up to a ')' or ';'
+    fn bytes(&mut self) -> Result<Vec<u8>, CompileError> {
+        let mut bytes = Vec::new();
+        loop {
+            self.skip_space();
+            match self.peek() {
+                Some(')') | Some(';') => return Ok(bytes),
+                Some('"') => {
+                    self.pos += 1;
+                    loop {
+                        match self.peek() {
+                            Some('"') => break,
+                            Some(c) => {
+                                let mut buf = [0; 4];
+                                bytes.extend(c.encode_utf8(&mut buf).as_bytes());
+                                self.pos += 1;
+                            }
+                            None => return Err(self.error("Unterminated string")),
+                        }
+                    }
+                    self.pos += 1;
+                }
+                Some(_) => {
+                    let start = self.pos;
+                    let digits = self.word();
+                    match u8::from_str_radix(&digits, 16) {
+                        Ok(byte) if digits.len() == 2 => bytes.push(byte),
+                        _ => {
+                            return Err(self
+                                .error_at(start, "Expected a two digit hex byte, a string or ')'"))
+                        }
+                    }
+                }
+                None => return Err(self.error("Expected ')'")),
+            }
+        }
+    }
+    /// The optional ';' encoding at the end of a string pattern,
+    /// and the closing ')'
+    fn encoding(&mut self, pattern: Pattern) -> Result<Pattern, CompileError> {
+        let pattern = if self.consume(';') {
+            pattern.encoding(self.encoding_name()?)
+        } else {
+            pattern
+        };
+        self.expect(')')?;
+        Ok(pattern)
+    }
+    fn encoding_name(&mut self) -> Result<Encoding, CompileError> {
+        self.skip_space();
+        let start = self.pos;
+        Ok(match self.word().as_str() {
+            "utf8" => Encoding::Utf8,
+            "utf16" => Encoding::Utf16,
+            "utf16le" => Encoding::Utf16Le,
+            "utf16be" => Encoding::Utf16Be,
+            "latin1" => Encoding::Latin1,
+            "ascii" => Encoding::Ascii,
+            "windows1252" => Encoding::Windows1252,
+            _ => return Err(self.error_at(start, "Expected an encoding")),
+        })
+    }
+    fn checksum_name(&mut self) -> Result<Checksum, CompileError> {
+        self.skip_space();
+        let start = self.pos;
+        Ok(match self.word().as_str() {
+            "crc32" => Checksum::Crc32,
+            "crc32c" => Checksum::Crc32c,
+            "crc16_arc" => Checksum::Crc16Arc,
+            "crc16_modbus" => Checksum::Crc16Modbus,
+            "crc16_ccitt" => Checksum::Crc16Ccitt,
+            "crc16_xmodem" => Checksum::Crc16Xmodem,
+            "crc16_x25" => Checksum::Crc16X25,
+            "adler32" => Checksum::Adler32,
+            "fletcher16" => Checksum::Fletcher16,
+            "fletcher32" => Checksum::Fletcher32,
+            "sum8" => Checksum::Sum8,
+            "sum16" => Checksum::Sum16,
+            "sum32" => Checksum::Sum32,
+            "xor8" => Checksum::Xor8,
+            _ => return Err(self.error_at(start, "Expected a checksum algorithm")),
+        })
+    }
+    /// An integer or a string
+    fn literal(&mut self) -> Result<Data, CompileError> {
+        self.skip_space();
+        let start = self.pos;
+        if self.consume('"') {
+            let mut string = String::new();
+            loop {
+                match self.peek() {
+                    Some('"') => break,
+                    Some(c) => string.push(c),
+                    None => return Err(self.error("Unterminated string")),
+                }
+                self.pos += 1;
+            }
+            self.pos += 1;
+            return Ok(string.into());
+        }
+        let negative = self.consume('-');
+        let word = self.word();
+        let value = if let Some(hex) = word.strip_prefix("0x") {
+            u128::from_str_radix(hex, 16)
+        } else {
+            word.parse()
+        };
+        match value {
+            Ok(u) if negative && u <= i128::MAX as u128 => Ok(Data::fi128(-(u as i128))),
+            Ok(u) if !negative => Ok(Data::fu128(u)),
+            _ => Err(self.error_at(start, "Expected an integer or a string")),
+        }
+    }
+
+    /// Binary operators, grouped from lowest to highest precedence
+    const OPERATORS: &'static [&'static [&'static str]] = &[
+        &["==", "!=", "<=", ">=", "<", ">"],
+        &["|"],
+        &["&"],
+        &["<<", ">>"],
+        &["+", "-"],
+        &["*", "/", "%"],
+    ];
+
+    fn expr(&mut self) -> Result<Expr, CompileError> {
+        self.binary(0)
+    }
+    fn binary(&mut self, level: usize) -> Result<Expr, CompileError> {
+        if level == Self::OPERATORS.len() {
+            return self.unary();
+        }
+        let mut lhs = self.binary(level + 1)?;
+        while let Some(op) = self.operator(Self::OPERATORS[level]) {
+            let rhs = self.binary(level + 1)?;
+            lhs = match op {
+                "==" => lhs.equals(rhs),
+                "!=" => lhs.not_equals(rhs),
+                "<=" => lhs.le(rhs),
+                ">=" => lhs.ge(rhs),
+                "<" => lhs.lt(rhs),
+                ">" => lhs.gt(rhs),
+                "|" => lhs | rhs,
+                "&" => lhs & rhs,
+                "<<" => lhs << rhs,
+                ">>" => lhs >> rhs,
+                "+" => lhs + rhs,
+                "-" => lhs - rhs,
+                "*" => lhs * rhs,
+                "/" => lhs / rhs,
+                _ => lhs % rhs,
+            };
+        }
+        Ok(lhs)
+    }
+
+    /// Consumes the next operator if it is one of ops
+    fn operator(&mut self, ops: &[&'static str]) -> Option<&'static str> {
+        self.skip_space();
+        // find the longest operator here, so that e.g. '<' is not
+        // mistaken for the start of '<<'
+        let longest = Self::OPERATORS
+            .iter()
+            .flat_map(|ops| ops.iter())
+            .filter(|op| {
+                op.chars()
+                    .enumerate()
+                    .all(|(i, c)| self.chars.get(self.pos + i) == Some(&c))
+            })
+            .max_by_key(|op| op.len())?;
+        if ops.contains(longest) {
+            self.pos += longest.len();
+            Some(longest)
+        } else {
+            None
+        }
+    }
+    fn unary(&mut self) -> Result<Expr, CompileError> {
+        if self.consume('-') {
+            return Ok(constant(0) - self.unary()?);
+        }
+        if self.consume('(') {
+            let expr = self.expr()?;
+            self.expect(')')?;
+            return Ok(expr);
+        }
+        self.skip_space();
+        if self
+            .peek()
+            .map(|c| c.is_ascii_digit() || c == '"')
+            .unwrap_or(false)
+        {
+            return Ok(self.literal()?.into());
+        }
+        let start = self.pos;
+        let word = self.word();
+        if word.is_empty() {
+            Err(self.error_at(start, "Expected an expression"))
+        } else if self.consume('(') {
+            let mut args = Vec::new();
+            while !self.consume(')') {
+                args.push(self.expr()?);
+                if !self.consume(',') {
+                    self.expect(')')?;
+                    break;
+                }
+            }
+            let mut args = args.into_iter();
+            match (word.as_str(), args.len()) {
+                ("offset", 0) => Ok(offset()),
+                ("len", 0) => Ok(input_len()),
+                ("min", 2) => Ok(args.next().unwrap().min(args.next().unwrap())),
+                ("max", 2) => Ok(args.next().unwrap().max(args.next().unwrap())),
+                ("if", 3) => {
+                    let cond = args.next().unwrap();
+                    Ok(cond.if_then_else(args.next().unwrap(), args.next().unwrap()))
+                }
+                _ => Err(self.error_at(
+                    start,
+                    format!("Unknown function {:?} with {} arguments", word, args.len()),
+                )),
+            }
+        } else {
+            Ok(getvar(word))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::render;
+    use crate::tests::ints;
+    use crate::Data;
+    use crate::ErrorKind;
+    use crate::ParseError;
+    use crate::Pattern;
+
+    fn parse(src: &str, bytes: &[u8]) -> Result<Data, ParseError> {
+        Pattern::compile(src).unwrap().parse(bytes)
+    }
+
+    #[test]
+    fn compile() {
+        let pattern =
+            Pattern::compile("magic(42 4D) u32le:file_size u16 u16 u32le:pixel_offset").unwrap();
+        let bytes = render((0x4D42u16, 49206u32, 0u16, 0u16, 54u32));
+        assert_eq!(
+            pattern.parse(&bytes).unwrap(),
+            Data::fseq(vec![
+                Data::fbytes(vec![0x42, 0x4D]),
+                Data::Int(49206),
+                Data::Int(0),
+                Data::Int(0),
+                Data::Int(54),
+            ])
+        );
+
+        let pattern = Pattern::compile(
+            "
+            magic(\"BM\") u8:len   # length of the array
+            ([u16be; len] | cstr)
+            ",
+        )
+        .unwrap();
+        let bytes = render((0x4D42u16, 2u8, 1u16, 2u16));
+        assert_eq!(
+            pattern.parse(&bytes).unwrap(),
+            Data::fseq(vec![
+                Data::fbytes(vec![0x42, 0x4D]),
+                Data::Int(2),
+                Data::fseq(vec![Data::Int(256), Data::Int(512)]),
+            ])
+        );
+    }
+
+    #[test]
+    fn records() {
+        let pattern =
+            Pattern::compile("{ width = u32:w, height = u16be, rest = [u8; w], }").unwrap();
+        let bytes = render((1u32, 2u16, 3u8));
+        let data = pattern.parse(&bytes).unwrap();
+        assert_eq!(
+            data,
+            Data::fmap(vec![
+                ("width", Data::Int(1)),
+                ("height", Data::Int(512)),
+                ("rest", vec![Data::Int(3)].into()),
+            ])
+        );
+        assert_eq!(data.get("height"), Some(&Data::Int(512)));
+        assert_eq!(pattern.render(&data).unwrap(), bytes);
+    }
+
+    #[test]
+    fn expressions() {
+        let pattern =
+            Pattern::compile("u8:w u8:bpp [u8; (w * bpp + 31) / 32 * 4 - offset()]").unwrap();
+        let data = pattern.parse(&[3, 8, 1, 2]).unwrap();
+        assert_eq!(
+            data.seq().unwrap()[2],
+            Data::fseq(vec![Data::Int(1), Data::Int(2)])
+        );
+
+        let pattern =
+            Pattern::compile("u8:n [u8; if(n << 1 >= 4 | 0, max(n, 1), -1 + 1)]").unwrap();
+        assert_eq!(
+            pattern.parse(&[2, 5, 6]).unwrap().seq().unwrap()[1]
+                .seq()
+                .unwrap()
+                .len(),
+            2
+        );
+        assert_eq!(
+            pattern.parse(&[1, 5, 6]).unwrap().seq().unwrap()[1]
+                .seq()
+                .unwrap()
+                .len(),
+            0
+        );
+
+        let error = Pattern::compile("[u8; min(1)]").err().unwrap();
+        assert_eq!((error.line, error.column), (1, 6));
+    }
+
+    #[test]
+    fn branches() {
+        let pattern = Pattern::compile(
+            "u8:tag switch(tag; 1 => u8, 2 => u16be, \"x\" => cstr, _ => if(tag > 8, u8 u8, ()),)",
+        )
+        .unwrap();
+        let second = |bytes: &[u8]| {
+            pattern
+                .parse(bytes)
+                .map(|data| data.seq().unwrap()[1].clone())
+        };
+        assert_eq!(second(&[1, 5]).unwrap(), Data::Int(5));
+        assert_eq!(second(&[2, 1, 0]).unwrap(), Data::Int(256));
+        assert_eq!(
+            second(&[9, 1, 2]).unwrap(),
+            Data::fseq(vec![Data::Int(1), Data::Int(2)])
+        );
+        assert_eq!(second(&[3]).unwrap(), Data::fseq(vec![]));
+
+        let error = Pattern::compile("switch(1; _ => u8, _ => u8)")
+            .err()
+            .unwrap();
+        assert_eq!((error.line, error.column), (1, 20));
+    }
+
+    #[test]
+    fn repeats() {
+        assert_eq!(parse("[u8; ..]", &[1, 2, 3]).unwrap(), ints(&[1, 2, 3]));
+        assert_eq!(parse("[u16; ..]", &[1, 0, 3]).unwrap_err().offset, Some(2));
+        assert_eq!(
+            parse("[magic(01); *] u8", &[1, 1, 2])
+                .unwrap()
+                .seq()
+                .unwrap()[0]
+                .seq()
+                .unwrap()
+                .len(),
+            2
+        );
+        assert!(parse("[magic(01); +]", &[2]).is_err());
+        assert_eq!(
+            parse("[u8:x; until x == 0]", &[3, 0, 5]).unwrap(),
+            ints(&[3, 0])
+        );
+        assert_eq!(
+            parse("u8:x [u8:x; while x != 0]", &[3, 0, 5]).unwrap(),
+            Data::fseq(vec![Data::Int(3), ints(&[0])])
+        );
+    }
+
+    #[test]
+    fn sized() {
+        assert_eq!(
+            parse("u8:n sized(n, u8) u8", &[2, 5, 6, 7]).unwrap(),
+            ints(&[2, 5, 7])
+        );
+        assert!(parse("u8:n sized_exact(n, u8) u8", &[2, 5, 6, 7]).is_err());
+    }
+
+    #[test]
+    fn offsets() {
+        let data = parse("u8:ptr at(ptr, u8) skip(1) u8", &[3, 5, 6, 7]).unwrap();
+        assert_eq!(
+            data,
+            Data::fseq(vec![
+                Data::Int(3),
+                Data::Int(7),
+                Data::fbytes(vec![5]),
+                Data::Int(6)
+            ])
+        );
+        let data = parse("seek(2) u8", &[3, 5, 6]).unwrap();
+        assert_eq!(data, Data::fseq(vec![Data::Int(2), Data::Int(6)]));
+        assert!(parse("seek(4)", &[3, 5, 6]).is_err());
+    }
+
+    #[test]
+    fn lookahead() {
+        let pattern = Pattern::compile("peek(u8) not(magic(00)) u8 rest eof").unwrap();
+        assert_eq!(
+            pattern.parse(&[1, 2]).unwrap(),
+            Data::fseq(vec![
+                Data::Int(1),
+                Data::fseq(vec![]),
+                Data::Int(1),
+                Data::fbytes(vec![2]),
+                Data::fseq(vec![])
+            ])
+        );
+        assert!(pattern.parse(&[0, 2]).is_err());
+    }
+
+    #[test]
+    fn checks() {
+        let pattern =
+            Pattern::compile("in_range(u8; 1, 3) one_of(u8; 7, 9) expect_eq(u8; len())").unwrap();
+        assert!(pattern.parse(&[2, 9, 3]).is_ok());
+        for bytes in &[[0, 9, 3], [2, 8, 3], [2, 9, 4]] {
+            let error = pattern.parse(bytes).unwrap_err();
+            assert_eq!(error.kind, ErrorKind::Validation);
+        }
+    }
+
+    #[test]
+    fn symbols() {
+        let pattern = Pattern::compile(
+            "enumerate(u8; 0 = \"RGB\", 1 = \"RLE8\", _ = tag) flags(u8; 1 = \"R\", 2 = \"W\")",
+        )
+        .unwrap();
+        let data = pattern.parse(&[1, 7]).unwrap();
+        assert_eq!(
+            data,
+            Data::fseq(vec![
+                "RLE8".into(),
+                Data::fseq(vec!["R".into(), "W".into(), Data::Int(4)])
+            ])
+        );
+        assert_eq!(pattern.render(&data).unwrap(), [1, 7]);
+        assert_eq!(
+            pattern.parse(&[5, 0]).unwrap().seq().unwrap()[0],
+            Data::fseq(vec!["Unknown".into(), Data::Int(5)])
+        );
+        assert!(Pattern::compile("enumerate(u8; 0 = 1)").is_err());
+        assert!(Pattern::compile("flags(u8; -1 = \"A\")").is_err());
+    }
+
+    #[test]
+    fn endian() {
+        let pattern =
+            Pattern::compile("byte_order(\"II\"; \"MM\"):order with_endian(order, u16dyn) u16dyn")
+                .unwrap();
+        let data = pattern.parse(b"MM\x00\x2A\x2A\x00").unwrap();
+        assert_eq!(
+            data,
+            Data::fseq(vec!["big".into(), Data::Int(42), Data::Int(42)])
+        );
+        assert_eq!(pattern.render(&data).unwrap(), b"MM\x00\x2A\x2A\x00");
+    }
+
+    #[test]
+    fn bits() {
+        assert_eq!(
+            parse("bits(4) bits(4) u8", &[0x12, 3]).unwrap(),
+            ints(&[1, 2, 3])
+        );
+        assert_eq!(parse("bits_lsb(3) u8", &[0x12, 3]).unwrap(), ints(&[2, 3]));
+        let data = parse("bitfield(u16be; flags = 3, len = 13,)", &[0x20, 5]).unwrap();
+        assert_eq!(
+            data,
+            Data::fmap(vec![("flags", Data::Int(1)), ("len", Data::Int(5))])
+        );
+        assert!(Pattern::compile("bits(65)").is_err());
+        assert_eq!(
+            parse("varint zigzag", &[0xAC, 0x02, 0x03]).unwrap(),
+            ints(&[300, -2])
+        );
+        assert!(Pattern::compile("bitfield(u64; a = 60, b = 5)").is_err());
+        assert!(Pattern::compile("bitfield(u8; a = 6, b = 3)").is_err());
+    }
+
+    #[test]
+    fn strings() {
+        let strings =
+            |strings: &[&str]| Data::fseq(strings.iter().map(|s| Data::from(*s)).collect());
+        assert_eq!(
+            parse("pascal_string(u8) string_until(0d 0a)", b"\x02hiab\r\n").unwrap(),
+            strings(&["hi", "ab"])
+        );
+        assert_eq!(
+            parse("fixed_string(4) fixed_string(3, \" \")", b"ab\0\0c  ").unwrap(),
+            strings(&["ab", "c"])
+        );
+        assert_eq!(
+            parse("cstr(utf16le) fixed_string(2; latin1)", b"h\0i\0\0\0\xFC\0").unwrap(),
+            strings(&["hi", "\u{FC}"])
+        );
+        assert_eq!(
+            parse("string_until(00 00; utf16be)", b"\0a\0\0").unwrap(),
+            Data::from("a")
+        );
+        assert!(Pattern::compile("cstr(utf7)").is_err());
+        assert!(Pattern::compile("string_until()").is_err());
+    }
+
+    #[test]
+    fn syntax_errors() {
+        let error = Pattern::compile("u8 u33 u8").err().unwrap();
+        assert_eq!((error.line, error.column), (1, 4));
+
+        let error = Pattern::compile("u8\n  [u8; 3").err().unwrap();
+        assert_eq!((error.line, error.column), (2, 9));
+
+        let error = Pattern::compile("magic(4D 4)").err().unwrap();
+        assert_eq!((error.line, error.column), (1, 10));
+
+        let error = Pattern::compile("u8 )").err().unwrap();
+        assert_eq!((error.line, error.column), (1, 4));
+    }
+
+    #[test]
+    fn checksums() {
+        let pattern = Pattern::compile(
+            "seek(offset()):start cstr checksum(crc16_ccitt; start, offset() - 1; u16be)",
+        )
+        .unwrap();
+        assert!(pattern.parse(b"123456789\0\x29\xB1").is_ok());
+        let error = pattern.parse(b"123456789\0\x29\xB2").unwrap_err();
+        assert_eq!(error.kind, ErrorKind::ChecksumMismatch);
+        assert!(Pattern::compile("checksum(md5; 0, 1; u8)").is_err());
+    }
+}
@@ -1,20 +1,67 @@
-use crate::err;
+use crate::reader::io_error;
 use crate::Data;
+use crate::Env;
+use crate::ErrorKind;
 use crate::ParseError;
+use crate::Span;
 use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::rc::Rc;
 
 pub struct Context<'a> {
     scope_stack: Vec<Scope>,
     pos: usize,
-    bytes: &'a [u8],
+    source: Source<'a>,
+
+    // number of bits already read from the byte at pos
+    // (see read_bits); byte reads skip any bits that are left
+    bit: usize,
+
+    // the (start, end) that reads are confined to, if any: reads past
+    // end fail, even if there are more bytes, and seeks before start
+    // do too (see Pattern::Sized). Without one, the input ends at len
+    limits: Option<(usize, usize)>,
+
+    // only collected when requested (see Pattern::parse_spans)
+    pub(crate) spans: Option<Vec<Span>>,
+
+    // whether more input may follow (see Pattern::parse_partial)
+    pub(crate) partial: bool,
+
+    // byte order of the endian-neutral patterns (see Pattern::WithEndian)
+    pub(crate) endian: Endian,
+}
+
+/// Where a Context gets its bytes from
+enum Source<'a> {
+    Bytes(&'a [u8]),
+    Reader(&'a mut dyn Fill, usize), // with the length of the stream
+}
+
+/// Random access to a stream that is not all in memory (see Buffered)
+pub(crate) trait Fill {
+    /// Gets the n bytes at pos, which are known to be in the stream
+    fn fill(&mut self, pos: usize, n: usize) -> io::Result<&[u8]>;
 }
 
 impl<'a> Context<'a> {
-    pub(crate) fn new(bytes: &[u8]) -> Context {
+    pub(crate) fn new(bytes: &[u8]) -> Context<'_> {
+        Context::with_source(Source::Bytes(bytes))
+    }
+    pub(crate) fn from_reader(reader: &'a mut dyn Fill, len: usize) -> Context<'a> {
+        Context::with_source(Source::Reader(reader, len))
+    }
+    fn with_source(source: Source<'a>) -> Context<'a> {
         Context {
             scope_stack: vec![Scope(HashMap::new())],
             pos: 0,
-            bytes,
+            source,
+            bit: 0,
+            limits: None,
+            spans: None,
+            partial: false,
+            endian: Endian::Little,
         }
     }
     pub fn scope(&self) -> &Scope {
@@ -23,17 +70,133 @@ impl<'a> Context<'a> {
     pub fn scope_mut(&mut self) -> &mut Scope {
         self.scope_stack.last_mut().unwrap()
     }
-    pub fn peek(&self, n: usize) -> Result<&'a [u8], ParseError> {
-        match self.bytes.get(self.pos..self.pos + n) {
-            Some(s) => Ok(s),
-            None => err("Tried to peek beyond end"),
+    pub fn env(&self) -> Env<'_> {
+        Env {
+            scope: self.scope(),
+            offset: self.pos,
+            len: self.len(),
+        }
+    }
+    pub fn peek(&mut self, n: usize) -> Result<&[u8], ParseError> {
+        let start = self.aligned_pos();
+        if start + n > self.end() {
+            return Err(self.eof_error(n));
+        }
+        self.fetch(start, n)
+    }
+    pub fn read(&mut self, n: usize) -> Result<&[u8], ParseError> {
+        let start = self.aligned_pos();
+        if start + n > self.end() {
+            return Err(self.eof_error(n));
+        }
+        self.pos = start + n;
+        self.bit = 0;
+        self.fetch(start, n)
+    }
+    /// The bytes from start up to end, which may be anywhere
+    /// in the input regardless of the current position or limit
+    pub(crate) fn range(&mut self, start: usize, end: usize) -> Result<&[u8], ParseError> {
+        self.fetch(start, end - start)
+    }
+    /// Moves past the next n bytes, returning them only if the input
+    /// is in memory anyway, so that skipping over a stream reads nothing
+    pub(crate) fn skip(&mut self, n: usize) -> Result<Option<&[u8]>, ParseError> {
+        if let Source::Bytes(_) = self.source {
+            return self.read(n).map(Some);
+        }
+        let start = self.aligned_pos();
+        if start + n > self.end() {
+            return Err(self.eof_error(n));
+        }
+        self.pos = start + n;
+        self.bit = 0;
+        Ok(None)
+    }
+    fn fetch(&mut self, start: usize, n: usize) -> Result<&[u8], ParseError> {
+        match &mut self.source {
+            Source::Bytes(bytes) => Ok(&bytes[start..start + n]),
+            Source::Reader(reader, _) => reader
+                .fill(start, n)
+                .map_err(|error| io_error(error).with_offset(start)),
+        }
+    }
+    /// The position of the next byte read, skipping any bits
+    /// left in a partially read byte
+    pub(crate) fn aligned_pos(&self) -> usize {
+        if self.bit > 0 {
+            self.pos + 1
+        } else {
+            self.pos
+        }
+    }
+
+    /// Reads n (at most 64) bits, without skipping to the next byte
+    /// first. With BitOrder::Msb, bits are taken from the most
+    /// significant end of each byte and the first one read ends up
+    /// as the most significant bit of the result. With BitOrder::Lsb
+    /// it's the other way around
+    pub fn read_bits(&mut self, n: u32, order: BitOrder) -> Result<u64, ParseError> {
+        let available = (self.remaining() * 8).saturating_sub(self.bit);
+        if n as usize > available {
+            let needed = (n as usize - available).div_ceil(8);
+            if let Some(error) = self.incomplete(Some(needed)) {
+                return Err(error);
+            }
+            return Err(ParseError::new(
+                ErrorKind::UnexpectedEof,
+                format!("Tried to read {} bits, but only {} left", n, available),
+            )
+            .with_offset(self.pos)
+            .with_expected(n as i64)
+            .with_actual(available as i64));
+        }
+        let mut ret = 0;
+        for i in 0..n {
+            let byte = self.fetch(self.pos, 1)?[0] as u64;
+            match order {
+                BitOrder::Msb => ret = ret << 1 | (byte >> (7 - self.bit) & 1),
+                BitOrder::Lsb => ret |= (byte >> self.bit & 1) << i,
+            }
+            self.bit += 1;
+            if self.bit == 8 {
+                self.bit = 0;
+                self.pos += 1;
+            }
+        }
+        Ok(ret)
+    }
+    pub(crate) fn eof_error(&self, n: usize) -> ParseError {
+        let available = self.end().saturating_sub(self.aligned_pos());
+        if let Some(error) = self.incomplete(Some(n - available)) {
+            return error;
+        }
+        ParseError::new(
+            ErrorKind::UnexpectedEof,
+            format!("Tried to read {} bytes, but only {} left", n, available),
+        )
+        .with_offset(self.pos)
+        .with_expected(n as i64)
+        .with_actual(available as i64)
+    }
+    /// The error for running out of input, if more of it may
+    /// still arrive. A limit set with set_limits is a real end
+    pub(crate) fn incomplete(&self, needed: Option<usize>) -> Option<ParseError> {
+        if self.partial && self.limits.is_none() {
+            Some(
+                ParseError::new(ErrorKind::Incomplete { needed }, "Need more input")
+                    .with_offset(self.pos),
+            )
+        } else {
+            None
         }
     }
-    pub fn read(&mut self, n: usize) -> Result<&'a [u8], ParseError> {
-        self.pos += n;
-        match self.bytes.get(self.pos - n..self.pos) {
-            Some(s) => Ok(s),
-            None => err("Tried to read beyond end"),
+    /// Names the span recorded since there were nspans spans,
+    /// if exactly one (i.e. a single primitive value) was recorded
+    pub(crate) fn name_span<D: Into<Data>>(&mut self, nspans: Option<usize>, name: D) {
+        if let (Some(spans), Some(n)) = (&mut self.spans, nspans) {
+            if spans.len() == n + 1 && spans[n].label.is_none() {
+                spans[n].label = Some(name.into());
+            }
         }
     }
     pub fn push_stack(&mut self) {
@@ -43,30 +206,256 @@ impl<'a> Context<'a> {
     pub fn pop_stack(&mut self) {
         self.scope_stack.pop().unwrap();
     }
-    pub fn save(&self) -> usize {
+    pub fn pos(&self) -> usize {
         self.pos
     }
-    pub fn restore(&mut self, pos: usize) {
+    pub fn remaining(&self) -> usize {
+        self.end().saturating_sub(self.pos)
+    }
+    /// Length of the whole input, regardless of any limit
+    pub fn len(&self) -> usize {
+        match &self.source {
+            Source::Bytes(bytes) => bytes.len(),
+            Source::Reader(_, len) => *len,
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Limits reads to between start and end (or lifts any limit with
+    /// None), returning the previous limits so that they can be put back
+    pub(crate) fn set_limits(&mut self, limits: Option<(usize, usize)>) -> Option<(usize, usize)> {
+        std::mem::replace(&mut self.limits, limits)
+    }
+    /// Where the current limit starts (see set_limits)
+    pub(crate) fn start(&self) -> usize {
+        self.limits.map_or(0, |(start, _)| start)
+    }
+    fn end(&self) -> usize {
+        self.limits.map_or(self.len(), |(_, end)| end)
+    }
+    pub fn save(&self) -> Mark {
+        Mark {
+            pos: self.pos,
+            bit: self.bit,
+        }
+    }
+    pub fn restore(&mut self, mark: Mark) {
+        self.pos = mark.pos;
+        self.bit = mark.bit;
+    }
+    /// Continues at the start of the byte at pos
+    pub(crate) fn set_pos(&mut self, pos: usize) {
         self.pos = pos;
+        self.bit = 0;
+    }
+}
+
+/// A position in the input, down to the bit (see Context::save)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mark {
+    pos: usize,
+    bit: usize,
+}
+
+/// The order in which bits are taken from (or put into) a byte
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitOrder {
+    Msb, // most significant bit first, as in most network protocols
+    Lsb, // least significant bit first, as in DEFLATE
+}
+
+/// Byte order of multi-byte values
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endian {
+    Little,
+    Big,
+}
+
+impl Endian {
+    /// The inverse of From<Endian> for Data
+    pub(crate) fn from_data(data: &Data) -> Option<Endian> {
+        match data.str() {
+            Some("little") => Some(Endian::Little),
+            Some("big") => Some(Endian::Big),
+            _ => None,
+        }
+    }
+}
+
+/// "little" or "big", e.g. for use as the Expr of Pattern::WithEndian
+impl From<Endian> for Data {
+    fn from(endian: Endian) -> Data {
+        match endian {
+            Endian::Little => "little".into(),
+            Endian::Big => "big".into(),
+        }
+    }
+}
+
+/// The name of a variable in the Scope.
+/// Prefer names over ids, so that independently written
+/// patterns don't accidentally share variables
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Var {
+    Id(i64),
+    Name(Rc<str>),
+}
+
+impl From<i64> for Var {
+    fn from(id: i64) -> Var {
+        Var::Id(id)
     }
 }
 
-pub struct Scope(HashMap<i64, Data>);
+impl From<&str> for Var {
+    fn from(name: &str) -> Var {
+        Var::Name(name.into())
+    }
+}
+
+impl From<String> for Var {
+    fn from(name: String) -> Var {
+        Var::Name(name.into())
+    }
+}
+
+impl From<&Var> for Var {
+    fn from(var: &Var) -> Var {
+        var.clone()
+    }
+}
+
+impl fmt::Display for Var {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Var::Id(id) => write!(f, "{}", id),
+            Var::Name(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+pub struct Scope(HashMap<Var, Data>);
 
 impl Scope {
-    pub fn get(&self, key: i64) -> Option<&Data> {
-        self.0.get(&key)
+    pub fn get<K: Into<Var>>(&self, key: K) -> Option<&Data> {
+        self.0.get(&key.into())
     }
-    pub fn get_or_error(&self, key: i64) -> Result<&Data, ParseError> {
-        match self.get(key) {
+    pub fn get_or_error<K: Into<Var>>(&self, key: K) -> Result<&Data, ParseError> {
+        let key = key.into();
+        match self.0.get(&key) {
             Some(val) => Ok(val),
-            None => err(format!("Key {:?} not found", key)),
+            None => Err(ParseError::new(
+                ErrorKind::UnknownKey,
+                format!("Variable {} not found", key),
+            )
+            .with_expected(format!("{}", key))),
         }
     }
-    pub fn set(&mut self, key: i64, value: Data) {
-        self.0.insert(key, value);
+    pub fn set<K: Into<Var>>(&mut self, key: K, value: Data) {
+        self.0.insert(key.into(), value);
     }
-    pub fn map(&self) -> &HashMap<i64, Data> {
+    pub fn map(&self) -> &HashMap<Var, Data> {
         &self.0
     }
 }
+
+/// The state threaded through Pattern::render,
+/// the mirror image of Context
+pub(crate) struct RenderContext {
+    scope_stack: Vec<Scope>,
+    out: Vec<u8>,
+
+    // number of bits already written to the last byte of out
+    bit: usize,
+
+    pub(crate) endian: Endian,
+}
+
+impl RenderContext {
+    pub(crate) fn new() -> RenderContext {
+        RenderContext {
+            scope_stack: vec![Scope(HashMap::new())],
+            out: Vec::new(),
+            bit: 0,
+            endian: Endian::Little,
+        }
+    }
+    pub(crate) fn scope(&self) -> &Scope {
+        self.scope_stack.last().unwrap()
+    }
+    pub(crate) fn scope_mut(&mut self) -> &mut Scope {
+        self.scope_stack.last_mut().unwrap()
+    }
+    pub(crate) fn env(&self) -> Env<'_> {
+        Env {
+            scope: self.scope(),
+            offset: self.out.len(),
+            len: self.out.len(),
+        }
+    }
+    pub(crate) fn push_stack(&mut self) {
+        let map = self.scope().0.clone();
+        self.scope_stack.push(Scope(map));
+    }
+    pub(crate) fn pop_stack(&mut self) {
+        self.scope_stack.pop().unwrap();
+    }
+    pub(crate) fn write(&mut self, bytes: &[u8]) {
+        self.bit = 0;
+        self.out.extend(bytes);
+    }
+    /// The inverse of Context::read_bits, with any bits
+    /// of the last byte that are not written left zero
+    pub(crate) fn write_bits(&mut self, value: u64, n: u32, order: BitOrder) {
+        for i in 0..n {
+            if self.bit == 0 {
+                self.out.push(0);
+            }
+            let bit = match order {
+                BitOrder::Msb => value >> (n - 1 - i) & 1,
+                BitOrder::Lsb => value >> i & 1,
+            };
+            let shift = match order {
+                BitOrder::Msb => 7 - self.bit,
+                BitOrder::Lsb => self.bit,
+            };
+            *self.out.last_mut().unwrap() |= (bit as u8) << shift;
+            self.bit = (self.bit + 1) % 8;
+        }
+    }
+    pub(crate) fn pos(&self) -> usize {
+        self.out.len()
+    }
+    pub(crate) fn written(&self) -> &[u8] {
+        &self.out
+    }
+    pub(crate) fn save(&self) -> RenderMark {
+        RenderMark {
+            len: self.out.len(),
+            bit: self.bit,
+            last: self.out.last().copied(),
+        }
+    }
+    /// Undoes everything written since the mark was saved
+    pub(crate) fn restore(&mut self, mark: RenderMark) {
+        self.out.truncate(mark.len);
+        self.bit = mark.bit;
+        if let (Some(last), Some(byte)) = (mark.last, self.out.last_mut()) {
+            *byte = last;
+        }
+    }
+    pub(crate) fn finish(self) -> Vec<u8> {
+        self.out
+    }
+}
+
+/// See RenderContext::save
+#[derive(Clone, Copy)]
+pub(crate) struct RenderMark {
+    len: usize,
+    bit: usize,
+
+    // the last byte may be partially filled with bits
+    last: Option<u8>,
+}
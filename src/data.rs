@@ -1,15 +1,56 @@
+use std::fmt;
 use std::rc::Rc;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Data {
     Int(i64),
+
+    // integers that do not fit in an Int. To keep equality simple,
+    // every integer has exactly one representation: the first of
+    // Int, UInt, Int128 and UInt128 that it fits in (see fi128).
+    // The 128 bit ones are behind an Rc to keep Data small
+    UInt(u64),
+    Int128(Rc<i128>),
+    UInt128(Rc<u128>),
+
     Float(f64),
     Bytes(Rc<Vec<u8>>),
     String(Rc<String>),
     Seq(Rc<Vec<Data>>),
+    Map(Rc<Vec<(String, Data)>>), // named fields, in order
 }
 
 impl Data {
+    /// Data from an unsigned 64 bit integer,
+    /// which need not fit in an Int
+    pub fn fu64(x: u64) -> Data {
+        if x <= i64::MAX as u64 {
+            Data::Int(x as i64)
+        } else {
+            Data::UInt(x)
+        }
+    }
+
+    /// Data from a 128 bit integer, in the smallest representation
+    pub fn fi128(x: i128) -> Data {
+        if i64::MIN as i128 <= x && x <= i64::MAX as i128 {
+            Data::Int(x as i64)
+        } else if 0 <= x && x <= u64::MAX as i128 {
+            Data::UInt(x as u64)
+        } else {
+            Data::Int128(x.into())
+        }
+    }
+
+    /// Data from an unsigned 128 bit integer, in the smallest representation
+    pub fn fu128(x: u128) -> Data {
+        if x <= i128::MAX as u128 {
+            Data::fi128(x as i128)
+        } else {
+            Data::UInt128(x.into())
+        }
+    }
+
     /// Data from a sequence
     pub fn fseq(vec: Vec<Data>) -> Data {
         Data::Seq(vec.into())
@@ -20,6 +61,25 @@ impl Data {
         Data::Bytes(bytes.into())
     }
 
+    /// Data from (name, value) pairs
+    pub fn fmap<S: Into<String>>(pairs: Vec<(S, Data)>) -> Data {
+        Data::Map(
+            pairs
+                .into_iter()
+                .map(|(name, value)| (name.into(), value))
+                .collect::<Vec<_>>()
+                .into(),
+        )
+    }
+
+    /// Looks up a field of a Map by name
+    pub fn get(&self, name: &str) -> Option<&Data> {
+        self.map()?
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value)
+    }
+
     pub fn i64(&self) -> Option<i64> {
         if let Data::Int(i) = self {
             Some(*i)
@@ -27,6 +87,22 @@ impl Data {
             None
         }
     }
+    /// Any integer that fits in an i128
+    pub fn i128(&self) -> Option<i128> {
+        match self {
+            Data::Int(i) => Some(*i as i128),
+            Data::UInt(u) => Some(*u as i128),
+            Data::Int128(i) => Some(**i),
+            _ => None,
+        }
+    }
+    /// Any non-negative integer
+    pub fn u128(&self) -> Option<u128> {
+        match self {
+            Data::UInt128(u) => Some(**u),
+            x => x.i128().filter(|i| *i >= 0).map(|i| i as u128),
+        }
+    }
     pub fn f64(&self) -> Option<f64> {
         if let Data::Float(f) = self {
             Some(*f)
@@ -62,6 +138,13 @@ impl Data {
             None
         }
     }
+    pub fn map(&self) -> Option<&Rc<Vec<(String, Data)>>> {
+        if let Data::Map(m) = self {
+            Some(m)
+        } else {
+            None
+        }
+    }
     pub fn u8(&self) -> Option<u8> {
         self.i64().map(|i| i as u64 as u8)
     }
@@ -71,8 +154,12 @@ impl Data {
     pub fn u32(&self) -> Option<u32> {
         self.i64().map(|i| i as u64 as u32)
     }
+    /// Any integer that fits in a u64, so not a negative one
     pub fn u64(&self) -> Option<u64> {
-        self.i64().map(|i| i as u64 as u64)
+        match self {
+            Data::UInt(u) => Some(*u),
+            x => x.i64().filter(|i| *i >= 0).map(|i| i as u64),
+        }
     }
     pub fn i8(&self) -> Option<i8> {
         self.i64().map(|i| i as i8)
@@ -165,3 +252,43 @@ impl From<&[Data]> for Data {
         Data::Seq(x.to_vec().into())
     }
 }
+
+impl From<Vec<(String, Data)>> for Data {
+    fn from(x: Vec<(String, Data)>) -> Data {
+        Data::Map(x.into())
+    }
+}
+
+impl fmt::Display for Data {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Data::Int(i) => write!(f, "{}", i),
+            Data::UInt(i) => write!(f, "{}", i),
+            Data::Int128(i) => write!(f, "{}", i),
+            Data::UInt128(i) => write!(f, "{}", i),
+            Data::Float(x) => write!(f, "{}", x),
+            Data::Bytes(bytes) => write!(f, "{:?}", bytes),
+            Data::String(s) => write!(f, "{:?}", s),
+            Data::Seq(seq) => {
+                write!(f, "[")?;
+                for (i, item) in seq.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+            Data::Map(pairs) => {
+                write!(f, "{{")?;
+                for (i, (name, value)) in pairs.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}: {}", name, value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
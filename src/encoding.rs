@@ -0,0 +1,170 @@
+use crate::ErrorKind;
+use crate::ParseError;
+use crate::RenderError;
+
+/// Character encoding of a string pattern (see Pattern::encoding)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+
+    // byte order from a leading byte order mark, which is dropped,
+    // or little endian without one. Rendered with a byte order mark
+    Utf16,
+
+    Latin1,      // ISO 8859-1, every byte is the char with the same number
+    Ascii,       // bytes above 0x7F are an error
+    Windows1252, // Latin-1 with printable chars in 0x80..0xA0
+}
+
+/// Windows-1252 chars for 0x80..0xA0. The five bytes it leaves
+/// undefined map to the C1 control chars, as in web browsers
+const WINDOWS_1252: [char; 32] = [
+    '\u{20AC}', '\u{0081}', '\u{201A}', '\u{0192}', '\u{201E}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{02C6}', '\u{2030}', '\u{0160}', '\u{2039}', '\u{0152}', '\u{008D}', '\u{017D}', '\u{008F}',
+    '\u{0090}', '\u{2018}', '\u{2019}', '\u{201C}', '\u{201D}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{02DC}', '\u{2122}', '\u{0161}', '\u{203A}', '\u{0153}', '\u{009D}', '\u{017E}', '\u{0178}',
+];
+
+impl Encoding {
+    /// Size in bytes of a code unit, which terminators
+    /// and padding have to be aligned to
+    pub(crate) fn unit(self) -> usize {
+        match self {
+            Encoding::Utf16Le | Encoding::Utf16Be | Encoding::Utf16 => 2,
+            _ => 1,
+        }
+    }
+
+    /// How a padding byte (in practice NUL or space) is encoded
+    pub(crate) fn padding(self, byte: u8) -> Vec<u8> {
+        match self {
+            // Utf16 is rendered little endian
+            Encoding::Utf16Le | Encoding::Utf16 => vec![byte, 0],
+            Encoding::Utf16Be => vec![0, byte],
+            _ => vec![byte],
+        }
+    }
+
+    pub(crate) fn decode(self, bytes: &[u8]) -> Result<String, ParseError> {
+        match self {
+            Encoding::Utf8 => match std::str::from_utf8(bytes) {
+                Ok(s) => Ok(s.to_owned()),
+                Err(error) => {
+                    Err(ParseError::new(ErrorKind::Utf8, format!("{}", error)).with_actual(bytes))
+                }
+            },
+            Encoding::Utf16Le | Encoding::Utf16Be | Encoding::Utf16 => {
+                let (little_endian, bytes) = match (self, bytes) {
+                    (Encoding::Utf16Be, _) => (false, bytes),
+                    (Encoding::Utf16, [0xFE, 0xFF, rest @ ..]) => (false, rest),
+                    (Encoding::Utf16, [0xFF, 0xFE, rest @ ..]) => (true, rest),
+                    _ => (true, bytes),
+                };
+                if bytes.len() % 2 != 0 {
+                    return Err(encoding_error(bytes, "UTF-16 with an odd number of bytes"));
+                }
+                let units = bytes.chunks(2).map(|unit| {
+                    if little_endian {
+                        u16::from_le_bytes([unit[0], unit[1]])
+                    } else {
+                        u16::from_be_bytes([unit[0], unit[1]])
+                    }
+                });
+                char::decode_utf16(units)
+                    .collect::<Result<String, _>>()
+                    .map_err(|error| encoding_error(bytes, &format!("{}", error)))
+            }
+            Encoding::Latin1 => Ok(bytes.iter().map(|b| *b as char).collect()),
+            Encoding::Ascii => match bytes.iter().position(|b| *b > 0x7F) {
+                Some(i) => Err(encoding_error(
+                    bytes,
+                    &format!("Non-ASCII byte {:#04x} at index {}", bytes[i], i),
+                )),
+                None => Ok(bytes.iter().map(|b| *b as char).collect()),
+            },
+            Encoding::Windows1252 => Ok(bytes
+                .iter()
+                .map(|b| match b {
+                    0x80..=0x9F => WINDOWS_1252[(b - 0x80) as usize],
+                    b => *b as char,
+                })
+                .collect()),
+        }
+    }
+
+    pub(crate) fn encode(self, s: &str) -> Result<Vec<u8>, RenderError> {
+        match self {
+            Encoding::Utf8 => Ok(s.as_bytes().to_vec()),
+            Encoding::Utf16Le => Ok(s.encode_utf16().flat_map(u16::to_le_bytes).collect()),
+            Encoding::Utf16Be => Ok(s.encode_utf16().flat_map(u16::to_be_bytes).collect()),
+            Encoding::Utf16 => {
+                let mut ret = vec![0xFF, 0xFE];
+                ret.extend(Encoding::Utf16Le.encode(s)?);
+                Ok(ret)
+            }
+            Encoding::Latin1 | Encoding::Ascii | Encoding::Windows1252 => s
+                .chars()
+                .map(|c| match (self, c as u32) {
+                    (Encoding::Ascii, 0..=0x7F) => Ok(c as u8),
+                    (Encoding::Latin1, 0..=0xFF) => Ok(c as u8),
+                    (Encoding::Windows1252, 0..=0x7F) | (Encoding::Windows1252, 0xA0..=0xFF) => {
+                        Ok(c as u8)
+                    }
+                    (Encoding::Windows1252, _) => match WINDOWS_1252.iter().position(|x| *x == c) {
+                        Some(i) => Ok(0x80 + i as u8),
+                        None => Err(unencodable(self, c)),
+                    },
+                    _ => Err(unencodable(self, c)),
+                })
+                .collect(),
+        }
+    }
+}
+
+fn encoding_error(bytes: &[u8], message: &str) -> ParseError {
+    ParseError::new(ErrorKind::Encoding, message).with_actual(bytes)
+}
+
+fn unencodable(encoding: Encoding, c: char) -> RenderError {
+    RenderError::new(format!("{:?} cannot encode {:?}", encoding, c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Encoding;
+
+    #[test]
+    fn roundtrip() {
+        let cases: &[(Encoding, &str, &[u8])] = &[
+            (Encoding::Utf8, "ü", &[0xC3, 0xBC]),
+            (Encoding::Utf16Le, "a€", &[0x61, 0x00, 0xAC, 0x20]),
+            (Encoding::Utf16Be, "a€", &[0x00, 0x61, 0x20, 0xAC]),
+            (Encoding::Utf16Le, "😀", &[0x3D, 0xD8, 0x00, 0xDE]),
+            (Encoding::Utf16, "a", &[0xFF, 0xFE, 0x61, 0x00]),
+            (Encoding::Latin1, "ü\u{80}", &[0xFC, 0x80]),
+            (Encoding::Ascii, "a~", &[0x61, 0x7E]),
+            (Encoding::Windows1252, "€ü\u{81}", &[0x80, 0xFC, 0x81]),
+        ];
+        for (encoding, s, bytes) in cases {
+            assert_eq!(encoding.decode(bytes).unwrap(), *s, "{:?}", encoding);
+            assert_eq!(encoding.encode(s).unwrap(), *bytes, "{:?}", encoding);
+        }
+        assert_eq!(
+            Encoding::Utf16.decode(&[0xFE, 0xFF, 0x00, 0x61]).unwrap(),
+            "a"
+        );
+        assert_eq!(Encoding::Utf16.decode(&[0x61, 0x00]).unwrap(), "a");
+    }
+
+    #[test]
+    fn errors() {
+        assert!(Encoding::Utf16Le.decode(&[0x61]).is_err());
+        assert!(Encoding::Utf16Le.decode(&[0x3D, 0xD8]).is_err()); // lone surrogate
+        assert!(Encoding::Ascii.decode(&[0x80]).is_err());
+        assert!(Encoding::Ascii.encode("ü").is_err());
+        assert!(Encoding::Latin1.encode("€").is_err());
+        assert!(Encoding::Windows1252.encode("\u{80}").is_err());
+    }
+}
@@ -0,0 +1,213 @@
+use crate::Data;
+use std::fmt;
+
+/// Broad category of a parse failure
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    UnexpectedEof,
+    MagicMismatch,
+    Utf8,
+    Encoding, // invalid text in an encoding other than UTF-8
+    TypeMismatch,
+    UnknownKey,
+    Overflow,
+    Validation,       // a value failed a check (see Pattern::Validate)
+    ChecksumMismatch, // expected is the computed checksum, actual the stored one
+    Io,               // the underlying reader failed (see Pattern::parse_reader)
+
+    // the input ended, but more of it might still arrive
+    // (see Pattern::parse_partial). needed is how many more
+    // bytes are known to be missing, if that is known
+    Incomplete { needed: Option<usize> },
+
+    Custom,
+}
+
+/// One step in the chain of nested patterns that leads
+/// from the root pattern to the one that failed
+#[derive(Debug, Clone, PartialEq)]
+pub enum PathSegment {
+    AllOf(usize),  // index of the failing member
+    AnyOf(usize),  // index of the (last) alternative tried
+    Array(usize),  // index of the failing element
+    Label(Data),   // name given with Pattern::label
+    Field(String), // name of the failing field of a record
+    Case(Data),    // case of If ("then" or "else") or Switch (key or "default")
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub kind: ErrorKind,
+    pub message: String,
+
+    /// byte offset into the input at which the failure was detected
+    pub offset: Option<usize>,
+
+    /// outermost pattern first
+    pub path: Vec<PathSegment>,
+
+    pub expected: Option<Data>,
+    pub actual: Option<Data>,
+}
+
+impl ParseError {
+    pub fn new<S: Into<String>>(kind: ErrorKind, message: S) -> ParseError {
+        ParseError {
+            kind,
+            message: message.into(),
+            offset: None,
+            path: Vec::new(),
+            expected: None,
+            actual: None,
+        }
+    }
+    pub fn custom<S: Into<String>>(message: S) -> ParseError {
+        ParseError::new(ErrorKind::Custom, message)
+    }
+    pub fn with_offset(mut self, offset: usize) -> ParseError {
+        self.offset = Some(offset);
+        self
+    }
+    pub fn with_expected<D: Into<Data>>(mut self, expected: D) -> ParseError {
+        self.expected = Some(expected.into());
+        self
+    }
+    pub fn with_actual<D: Into<Data>>(mut self, actual: D) -> ParseError {
+        self.actual = Some(actual.into());
+        self
+    }
+
+    /// Whether parsing may still succeed with more input
+    /// (see Pattern::parse_partial)
+    pub fn is_incomplete(&self) -> bool {
+        matches!(self.kind, ErrorKind::Incomplete { .. })
+    }
+
+    /// Sets the offset only if a more precise one was not
+    /// already recorded closer to the failure
+    pub(crate) fn or_offset(mut self, offset: usize) -> ParseError {
+        if self.offset.is_none() {
+            self.offset = Some(offset);
+        }
+        self
+    }
+
+    /// Records that this error happened inside the given segment
+    pub(crate) fn within(mut self, segment: PathSegment) -> ParseError {
+        self.path.insert(0, segment);
+        self
+    }
+}
+
+impl fmt::Display for PathSegment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PathSegment::AllOf(i) => write!(f, "all_of[{}]", i),
+            PathSegment::AnyOf(i) => write!(f, "any_of[{}]", i),
+            PathSegment::Array(i) => write!(f, "array[{}]", i),
+            PathSegment::Label(name) => write!(f, "label {}", name),
+            PathSegment::Field(name) => write!(f, "field {}", name),
+            PathSegment::Case(case) => write!(f, "case {}", case),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.kind)?;
+        if let Some(offset) = self.offset {
+            write!(f, " at offset {} (0x{:x})", offset, offset)?;
+        }
+        if !self.path.is_empty() {
+            write!(f, " in ")?;
+            for (i, segment) in self.path.iter().enumerate() {
+                if i > 0 {
+                    write!(f, " > ")?;
+                }
+                write!(f, "{}", segment)?;
+            }
+        }
+        write!(f, ": {}", self.message)?;
+        match (&self.expected, &self.actual) {
+            (Some(e), Some(a)) => write!(f, " (expected {}, got {})", e, a),
+            (Some(e), None) => write!(f, " (expected {})", e),
+            (None, Some(a)) => write!(f, " (got {})", a),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenderError {
+    /// Custom, unless the error came from a ParseError
+    /// (e.g. one from evaluating an Expr)
+    pub kind: ErrorKind,
+    pub message: String,
+
+    /// outermost pattern first
+    pub path: Vec<PathSegment>,
+}
+
+impl RenderError {
+    pub fn new<S: Into<String>>(message: S) -> RenderError {
+        RenderError {
+            kind: ErrorKind::Custom,
+            message: message.into(),
+            path: Vec::new(),
+        }
+    }
+
+    /// Records that this error happened inside the given segment
+    pub(crate) fn within(mut self, segment: PathSegment) -> RenderError {
+        self.path.insert(0, segment);
+        self
+    }
+}
+
+impl From<ParseError> for RenderError {
+    fn from(e: ParseError) -> RenderError {
+        RenderError {
+            kind: e.kind,
+            message: e.message,
+            path: e.path,
+        }
+    }
+}
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Could not render")?;
+        for (i, segment) in self.path.iter().enumerate() {
+            write!(f, "{}{}", if i == 0 { " in " } else { " > " }, segment)?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+impl std::error::Error for RenderError {}
+
+/// Syntax error in the textual pattern language (see Pattern::compile)
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompileError {
+    pub message: String,
+
+    /// 1-based
+    pub line: usize,
+
+    /// 1-based, counted in chars
+    pub column: usize,
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "line {}, column {}: {}",
+            self.line, self.column, self.message
+        )
+    }
+}
+
+impl std::error::Error for CompileError {}
@@ -1,15 +1,100 @@
 use crate::Data;
+use crate::ErrorKind;
 use crate::ParseError;
 use crate::Scope;
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+use std::ops;
 
-pub struct Expr(Box<dyn Fn(&Scope) -> Result<Data, ParseError>>);
+/// What an Expr can see while being evaluated
+pub struct Env<'a> {
+    pub scope: &'a Scope,
+
+    /// current position in the input (or output, when rendering)
+    pub offset: usize,
+
+    /// length of the whole input (or of the output so far, when rendering)
+    pub len: usize,
+}
+
+type ExprFn = Box<dyn Fn(&Env) -> Result<Data, ParseError>>;
+
+/// A value computed from the parse state, e.g. an array length.
+///
+/// Exprs can be combined with the usual arithmetic and bitwise
+/// operators (`+ - * / % << >> & |`) as well as the comparison
+/// and selection methods below. Integers stay integers (computed
+/// with 128 bits), and mixing in a float makes the result a float.
+/// Comparisons result in Int 1 (true) or 0 (false).
+pub struct Expr(ExprFn);
 
 impl Expr {
     pub fn new<F: Fn(&Scope) -> Result<Data, ParseError> + 'static>(f: F) -> Expr {
+        Expr(Box::new(move |env| f(env.scope)))
+    }
+
+    /// Like new, but the function also gets to see e.g. the current offset
+    pub fn with_env<F: Fn(&Env) -> Result<Data, ParseError> + 'static>(f: F) -> Expr {
         Expr(Box::new(f))
     }
-    pub fn eval(&self, scope: &Scope) -> Result<Data, ParseError> {
-        (self.0)(scope)
+    pub fn eval(&self, env: &Env) -> Result<Data, ParseError> {
+        (self.0)(env)
+    }
+
+    fn binop<E: Into<Expr>>(self, rhs: E, f: fn(Data, Data) -> Result<Data, ParseError>) -> Expr {
+        let rhs = rhs.into();
+        Expr::with_env(move |env| f(self.eval(env)?, rhs.eval(env)?))
+    }
+
+    pub fn equals<E: Into<Expr>>(self, rhs: E) -> Expr {
+        self.binop(rhs, |a, b| Ok(bool(a == b)))
+    }
+    pub fn not_equals<E: Into<Expr>>(self, rhs: E) -> Expr {
+        self.binop(rhs, |a, b| Ok(bool(a != b)))
+    }
+    pub fn lt<E: Into<Expr>>(self, rhs: E) -> Expr {
+        self.binop(rhs, |a, b| Ok(bool(compare(a, b, "<")?.is_lt())))
+    }
+    pub fn le<E: Into<Expr>>(self, rhs: E) -> Expr {
+        self.binop(rhs, |a, b| Ok(bool(compare(a, b, "<=")?.is_le())))
+    }
+    pub fn gt<E: Into<Expr>>(self, rhs: E) -> Expr {
+        self.binop(rhs, |a, b| Ok(bool(compare(a, b, ">")?.is_gt())))
+    }
+    pub fn ge<E: Into<Expr>>(self, rhs: E) -> Expr {
+        self.binop(rhs, |a, b| Ok(bool(compare(a, b, ">=")?.is_ge())))
+    }
+    pub fn min<E: Into<Expr>>(self, rhs: E) -> Expr {
+        self.binop(rhs, |a, b| {
+            Ok(if compare(a.clone(), b.clone(), "min")?.is_le() {
+                a
+            } else {
+                b
+            })
+        })
+    }
+    pub fn max<E: Into<Expr>>(self, rhs: E) -> Expr {
+        self.binop(rhs, |a, b| {
+            Ok(if compare(a.clone(), b.clone(), "max")?.is_ge() {
+                a
+            } else {
+                b
+            })
+        })
+    }
+
+    /// Evaluates to then_ if self is a non-zero int, and else_ if zero.
+    /// Only the selected branch is evaluated
+    pub fn if_then_else<T: Into<Expr>, E: Into<Expr>>(self, then_: T, else_: E) -> Expr {
+        let then_ = then_.into();
+        let else_ = else_.into();
+        Expr::with_env(move |env| {
+            if truthy(self.eval(env)?)? {
+                then_.eval(env)
+            } else {
+                else_.eval(env)
+            }
+        })
     }
 }
 
@@ -19,3 +104,170 @@ impl<T: Into<Data>> From<T> for Expr {
         Expr(Box::new(move |_| Ok(t.clone())))
     }
 }
+
+pub(crate) fn truthy(data: Data) -> Result<bool, ParseError> {
+    match data {
+        Data::Int(i) => Ok(i != 0),
+        // never zero
+        Data::UInt(_) | Data::Int128(_) | Data::UInt128(_) => Ok(true),
+        x => Err(type_error("Expected int for condition", x)),
+    }
+}
+
+fn bool(b: bool) -> Data {
+    Data::Int(b as i64)
+}
+
+fn type_error(message: &str, actual: Data) -> ParseError {
+    ParseError::new(ErrorKind::TypeMismatch, message).with_actual(actual)
+}
+
+pub(crate) fn compare(a: Data, b: Data, op: &str) -> Result<Ordering, ParseError> {
+    let ordering = match (&a, &b) {
+        (Data::Float(a), Data::Float(b)) => a.partial_cmp(b),
+        (Data::Float(a), b) => float(b).and_then(|b| a.partial_cmp(&b)),
+        (a, Data::Float(b)) => float(a).and_then(|a| a.partial_cmp(b)),
+        (Data::String(a), Data::String(b)) => Some(a.cmp(b)),
+        (Data::Bytes(a), Data::Bytes(b)) => Some(a.cmp(b)),
+        (a, b) => match (a.i128(), b.i128(), a.u128(), b.u128()) {
+            (Some(a), Some(b), _, _) => Some(a.cmp(&b)),
+            // only the integers above i128::MAX don't fit in an i128
+            (None, Some(_), Some(_), _) => Some(Ordering::Greater),
+            (Some(_), None, _, Some(_)) => Some(Ordering::Less),
+            (None, None, Some(a), Some(b)) => Some(a.cmp(&b)),
+            _ => None,
+        },
+    };
+    ordering.ok_or_else(|| type_error(&format!("Cannot compare with '{}'", op), vec![a, b].into()))
+}
+
+/// Any number as a float, for mixed int and float arithmetic
+pub(crate) fn float(data: &Data) -> Option<f64> {
+    match data {
+        Data::Float(x) => Some(*x),
+        Data::UInt128(u) => Some(**u as f64),
+        x => x.i128().map(|i| i as f64),
+    }
+}
+
+/// Applies an arithmetic operator, with checked integer math
+/// and promotion to float if either side is a float
+pub(crate) fn arith(
+    a: Data,
+    b: Data,
+    op: &str,
+    int: fn(i128, i128) -> Option<i128>,
+    float_op: Option<fn(f64, f64) -> f64>,
+) -> Result<Data, ParseError> {
+    if let (Some(x), Some(y)) = (a.i128(), b.i128()) {
+        return match int(x, y) {
+            Some(z) => Ok(Data::fi128(z)),
+            None => Err(ParseError::new(
+                ErrorKind::Overflow,
+                format!("Integer overflow or division by zero in {} {} {}", x, op, y),
+            )),
+        };
+    }
+    let is_float = |x: &Data| matches!(x, Data::Float(_));
+    match (float(&a), float(&b), float_op) {
+        (Some(x), Some(y), Some(f)) if is_float(&a) || is_float(&b) => Ok(Data::Float(f(x, y))),
+        _ => Err(type_error(
+            &format!("Invalid operands for '{}'", op),
+            vec![a, b].into(),
+        )),
+    }
+}
+
+macro_rules! expr_op {
+    ($Trait:ident, $method:ident, $op:expr, $int:expr, $float:expr) => {
+        impl<E: Into<Expr>> ops::$Trait<E> for Expr {
+            type Output = Expr;
+            fn $method(self, rhs: E) -> Expr {
+                self.binop(rhs, |a, b| arith(a, b, $op, $int, $float))
+            }
+        }
+    };
+}
+
+expr_op!(Sub, sub, "-", i128::checked_sub, Some(|x, y| x - y));
+expr_op!(Mul, mul, "*", i128::checked_mul, Some(|x, y| x * y));
+expr_op!(Div, div, "/", i128::checked_div, Some(|x, y| x / y));
+expr_op!(Rem, rem, "%", i128::checked_rem, Some(|x, y| x % y));
+expr_op!(
+    Shl,
+    shl,
+    "<<",
+    |x, y| u32::try_from(y).ok().and_then(|s| x.checked_shl(s)),
+    None
+);
+expr_op!(
+    Shr,
+    shr,
+    ">>",
+    |x, y| u32::try_from(y).ok().and_then(|s| x.checked_shr(s)),
+    None
+);
+expr_op!(BitAnd, bitand, "&", |x, y| Some(x & y), None);
+expr_op!(BitOr, bitor, "|", |x, y| Some(x | y), None);
+
+/// Like the other operators, but also concatenates strings
+impl<E: Into<Expr>> ops::Add<E> for Expr {
+    type Output = Expr;
+    fn add(self, rhs: E) -> Expr {
+        self.binop(rhs, |a, b| match (a, b) {
+            (Data::String(a), Data::String(b)) => Ok(format!("{}{}", a, b).into()),
+            (a, b) => arith(a, b, "+", i128::checked_add, Some(|x, y| x + y)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::prelude::*;
+    use crate::Data;
+    use crate::ErrorKind;
+
+    #[test]
+    fn arithmetic() {
+        // row size of a bitmap: rows are padded to multiples of 4 bytes
+        let parser = all_of((
+            U8.store("width"),
+            U8.store("bpp"),
+            array_of(
+                U8,
+                (getvar("width") * getvar("bpp") + 31) / 32 * 4 - offset(),
+            ),
+        ));
+        let data = parser.parse(&[3, 8, 1, 2]).unwrap();
+        assert_eq!(
+            data.seq().unwrap()[2],
+            Data::fseq(vec![Data::Int(1), Data::Int(2)])
+        );
+
+        let parser = array_of(U8, constant(1).lt(2).if_then_else(constant(1) << 1, 0));
+        assert_eq!(
+            parser.parse(&[5, 6]).unwrap(),
+            Data::fseq(vec![Data::Int(5), Data::Int(6)])
+        );
+
+        let parser = array_of(U8, constant(9).min(3).max(2) % 2);
+        assert_eq!(parser.parse(&[5]).unwrap(), Data::fseq(vec![Data::Int(5)]));
+
+        let parser = array_of(U8, constant("a") * 2);
+        assert_eq!(parser.parse(&[]).unwrap_err().kind, ErrorKind::TypeMismatch);
+
+        let parser = array_of(U8, constant(1) / 0);
+        assert!(parser.parse(&[]).is_err());
+
+        // shift amounts are not truncated to something in range
+        for shift in [
+            constant(1) << (1i64 << 32),
+            constant(1) << -1,
+            constant(1) >> (1i64 << 32),
+            constant(1) >> -1,
+        ] {
+            let error = array_of(U8, shift).parse(&[]).unwrap_err();
+            assert_eq!(error.kind, ErrorKind::Overflow);
+        }
+    }
+}
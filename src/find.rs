@@ -0,0 +1,124 @@
+use crate::Data;
+use crate::Pattern;
+
+/// The non-overlapping matches of a pattern in a buffer,
+/// as (offset, value) pairs (see Pattern::find_iter)
+pub struct Matches<'p, 'b> {
+    pattern: &'p Pattern,
+    bytes: &'b [u8],
+    pos: usize, // where to start looking for the next match
+
+    // bytes that every match starts with, so that only
+    // offsets where they are found need to be tried
+    prefix: Option<&'p [u8]>,
+}
+
+impl<'p, 'b> Matches<'p, 'b> {
+    pub(crate) fn new(pattern: &'p Pattern, bytes: &'b [u8]) -> Matches<'p, 'b> {
+        Matches {
+            pattern,
+            bytes,
+            pos: 0,
+            prefix: prefix(pattern),
+        }
+    }
+}
+
+impl<'p, 'b> Iterator for Matches<'p, 'b> {
+    type Item = (usize, Data);
+
+    fn next(&mut self) -> Option<(usize, Data)> {
+        while self.pos <= self.bytes.len() {
+            let start = match self.prefix {
+                Some(prefix) => match search(&self.bytes[self.pos..], prefix) {
+                    Some(i) => self.pos + i,
+                    None => break,
+                },
+                None => self.pos,
+            };
+            if let Ok((data, end)) = self.pattern.match_at(self.bytes, start) {
+                // an empty match would otherwise be found again
+                self.pos = end.max(start + 1);
+                return Some((start, data));
+            }
+            self.pos = start + 1;
+        }
+        self.pos = self.bytes.len() + 1;
+        None
+    }
+}
+
+/// The Exact bytes that a pattern starts with, if it always
+/// starts with some
+fn prefix(pattern: &Pattern) -> Option<&[u8]> {
+    match pattern {
+        Pattern::Exact(bytes) if !bytes.is_empty() => Some(bytes),
+        Pattern::AllOf(pats) => pats.first().and_then(prefix),
+        Pattern::Record(fields) => fields.first().and_then(|(_, pat)| prefix(pat)),
+        Pattern::Sized(pat, ..)
+        | Pattern::Store(pat, _)
+        | Pattern::Scoped(pat, _)
+        | Pattern::Map(pat, ..)
+        | Pattern::Label(pat, _)
+        | Pattern::Validate(pat, _)
+        | Pattern::WithEndian(_, pat)
+        | Pattern::Peek(pat) => prefix(pat),
+        _ => None,
+    }
+}
+
+fn search(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::prefix;
+    use crate::prelude::*;
+    use crate::Data;
+    use crate::ErrorKind;
+
+    #[test]
+    fn find() {
+        // PNG chunks have a length, a four letter type and a CRC
+        let chunk = record((
+            ("len", BE_U32.store("len")),
+            ("type", magic(b"IEND")),
+            ("crc", BE_U32),
+        ));
+        assert_eq!(prefix(&chunk), None);
+        let bytes = b"junk\x00\x00\x00\x00IEND\xAE\x42\x60\x82";
+        let (offset, data) = chunk.find(bytes).unwrap();
+        assert_eq!(offset, 4);
+        assert_eq!(data.get("crc"), Some(&Data::Int(0xAE426082)));
+        assert_eq!(chunk.find(b"\x00\x00\x00\x00IEN"), None);
+
+        let gif = all_of((magic(b"GIF8"), U8, magic(b"a"), LE_U16, LE_U16));
+        assert_eq!(prefix(&gif), Some(&b"GIF8"[..]));
+        let bytes = b"GIF8GIF89a\x02\x00\x01\x00GIF87a\x03\x00\x04\x00GIF8";
+        let found: Vec<_> = gif.find_iter(bytes).collect();
+        assert_eq!(
+            found.iter().map(|(offset, _)| *offset).collect::<Vec<_>>(),
+            vec![4, 14]
+        );
+        assert_eq!(found[1].1, gif.parse(&bytes[14..]).unwrap());
+
+        // matches do not overlap, and empty ones move on by a byte
+        assert_eq!(magic(b"aa").find_iter(b"aaaaa").count(), 2);
+        assert_eq!(all_of(()).find_iter(b"ab").count(), 3);
+    }
+
+    #[test]
+    fn parse_at() {
+        let bytes = b"\x00\x01\x02\x03";
+        assert_eq!(BE_U16.parse_at(bytes, 1), Ok(Data::Int(0x0102)));
+        assert_eq!(all_of(()).parse_at(bytes, 4), Ok(Data::fseq(vec![])));
+        let error = BE_U16.parse_at(bytes, 3).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::UnexpectedEof);
+        assert_eq!(error.offset, Some(3));
+        let error = U8.parse_at(bytes, 5).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::UnexpectedEof);
+    }
+}
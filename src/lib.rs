@@ -1,36 +1,68 @@
+mod check;
+mod checksum;
+mod compile;
 mod context;
 mod data;
+mod encoding;
+mod error;
 mod expr;
+mod find;
 mod parser;
 pub mod prelude;
 mod pvec;
+mod reader;
 mod render;
 pub mod samples;
+mod stream;
+mod varint;
 
+pub use check::Check;
+pub use checksum::Checksum;
+pub use context::BitOrder;
 pub use context::Context;
+pub use context::Endian;
+pub use context::Mark;
 pub use context::Scope;
+pub use context::Var;
 pub use data::Data;
+pub use encoding::Encoding;
+pub use error::CompileError;
+pub use error::ErrorKind;
+pub use error::ParseError;
+pub use error::PathSegment;
+pub use error::RenderError;
+pub use expr::Env;
 pub use expr::Expr;
-pub use parser::ParseError;
+pub use find::Matches;
+pub use parser::Fit;
 pub use parser::Pattern;
+pub use parser::Repeat;
+pub use parser::Span;
+pub use parser::StrLen;
+pub use parser::Unknown;
+pub use parser::MAX_REPEAT;
+pub use pvec::FieldVec;
 pub use pvec::PatternVec;
+pub use reader::Records;
 pub use render::render;
 pub use render::Render;
 pub use render::Renderable;
+pub use stream::StreamParser;
+pub use varint::Varint;
 
 fn err<T, S: Into<String>>(s: S) -> Result<T, ParseError> {
-    Err(ParseError::Other(s.into()))
-}
-
-pub enum Endian {
-    Little,
-    Big,
+    Err(ParseError::custom(s))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    /// A Seq of Ints, like an array of integers parses to
+    pub(crate) fn ints(ints: &[i64]) -> Data {
+        Data::fseq(ints.iter().map(|i| Data::Int(*i)).collect())
+    }
+
     #[test]
     fn sample() {
         let bytes = render((1234u64, 50000u16));
@@ -90,6 +122,7 @@ mod tests {
             ),
         ));
 
+        #[allow(clippy::upper_case_acronyms)]
         enum Key {
             LENGTH,
         }
@@ -154,4 +187,816 @@ mod tests {
             ])
         );
     }
+
+    #[test]
+    fn error_location() {
+        let bytes = render((0x4D42u16, 7u8, 8u8, 9u8));
+        let parser = {
+            use prelude::*;
+            all_of((magic(&[0x42, 0x4D]), array_of(U8.label("width"), 4)))
+        };
+
+        let error = parser.parse(&bytes).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::UnexpectedEof);
+        assert_eq!(error.offset, Some(5));
+        assert_eq!(
+            error.path,
+            vec![
+                PathSegment::AllOf(1),
+                PathSegment::Array(3),
+                PathSegment::Label("width".into()),
+            ]
+        );
+
+        let error = parser.parse(&render(0u16)).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::MagicMismatch);
+        assert_eq!(error.offset, Some(0));
+        assert_eq!(error.path, vec![PathSegment::AllOf(0)]);
+        assert_eq!(error.expected, Some(Data::fbytes(vec![0x42, 0x4D])));
+        assert_eq!(error.actual, Some(Data::fbytes(vec![0, 0])));
+    }
+
+    #[test]
+    fn records() {
+        let bytes = render((256u32, 64u32));
+        let parser = {
+            use prelude::*;
+            record((("width", U32), ("height", U32), ("depth", U16)))
+        };
+
+        let error = parser.parse(&bytes).unwrap_err();
+        assert_eq!(error.path, vec![PathSegment::Field("depth".into())]);
+
+        let data = parser.parse(&render((256u32, 64u32, 24u16))).unwrap();
+        assert_eq!(data.get("width"), Some(&Data::Int(256)));
+        assert_eq!(data.get("height"), Some(&Data::Int(64)));
+        assert_eq!(data.get("depth"), Some(&Data::Int(24)));
+        assert_eq!(data.get("size"), None);
+    }
+
+    #[test]
+    fn named_vars() {
+        let bytes = render((2u8, 7u16, 8u16, 1u8, 9u16));
+        let parser = {
+            use prelude::*;
+            all_of((
+                U8.store("count"),
+                array_of(U16, getvar("count")),
+                // an id that would have collided with an enum starting at 0
+                U8.store(0),
+                array_of(U16, getvar(0)),
+            ))
+        };
+        let data = parser.parse(&bytes).unwrap();
+        assert_eq!(
+            data,
+            Data::fseq(vec![
+                Data::Int(2),
+                Data::fseq(vec![Data::Int(7), Data::Int(8)]),
+                Data::Int(1),
+                Data::fseq(vec![Data::Int(9)]),
+            ])
+        );
+
+        let parser = prelude::array_of(prelude::U8, prelude::getvar("length"));
+        let error = parser.parse(&bytes).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::UnknownKey);
+        assert_eq!(error.message, "Variable length not found");
+    }
+
+    #[test]
+    fn scopes() {
+        // type-length-value records, each with their own 'length'
+        let bytes = render(((1u8, 2u8, 7u8, 8u8), (2u8, 1u8, 9u8)));
+        let tlv = {
+            use prelude::*;
+            all_of((
+                U8.store("type"),
+                U8.store("length"),
+                array_of(U8, getvar("length")),
+            ))
+        };
+        let parser = {
+            use prelude::*;
+            all_of((
+                array_of_scoped(tlv, 2),
+                all_of(()).map(|scope, _| Ok((scope.get("length").is_some() as i64).into())),
+            ))
+        };
+        let data = parser.parse(&bytes).unwrap();
+        assert_eq!(data.seq().unwrap()[0].seq().unwrap().len(), 2);
+        assert_eq!(data.seq().unwrap()[1], Data::Int(0));
+        assert_eq!(parser.render(&data).unwrap(), bytes);
+
+        let parser = {
+            use prelude::*;
+            all_of((
+                scoped_export(all_of((U8.store("a"), U8.store("b"))), vec!["b"]),
+                array_of(U8, getvar("b")),
+                array_of(U8, getvar("a")),
+            ))
+        };
+        let error = parser.parse(&render((1u8, 1u8, 1u8))).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::UnknownKey);
+        assert_eq!(error.path, vec![PathSegment::AllOf(2)]);
+
+        let parser = Pattern::compile("scope(u8:a u8:b; b) [u8; b]").unwrap();
+        assert_eq!(
+            parser.parse(&render((1u8, 2u8, 3u8, 4u8))).unwrap(),
+            Data::fseq(vec![
+                Data::fseq(vec![Data::Int(1), Data::Int(2)]),
+                Data::fseq(vec![Data::Int(3), Data::Int(4)]),
+            ])
+        );
+    }
+
+    #[test]
+    fn branches() {
+        let parser = {
+            use prelude::*;
+            all_of((
+                U32.store("header_size"),
+                switch(
+                    getvar("header_size"),
+                    vec![
+                        (12, all_of((U16, U16)).label("core")),
+                        (40, all_of((U32, U32)).label("info")),
+                    ],
+                    None,
+                ),
+                if_else(getvar("header_size").equals(12), U8, all_of(())),
+            ))
+        };
+
+        let data = parser.parse(&render((12u32, 1u16, 2u16, 3u8))).unwrap();
+        assert_eq!(
+            data.seq().unwrap()[1],
+            Data::fseq(vec![
+                "core".into(),
+                Data::fseq(vec![Data::Int(1), Data::Int(2)])
+            ])
+        );
+        assert_eq!(data.seq().unwrap()[2], Data::Int(3));
+        assert_eq!(
+            parser.render(&data).unwrap(),
+            render((12u32, 1u16, 2u16, 3u8))
+        );
+
+        let error = parser.parse(&render((40u32, 1u32))).unwrap_err();
+        assert_eq!(
+            error.path,
+            vec![
+                PathSegment::AllOf(1),
+                PathSegment::Case(Data::Int(40)),
+                PathSegment::Label("info".into()),
+                PathSegment::AllOf(1),
+            ]
+        );
+
+        let error = parser.parse(&render(108u32)).unwrap_err();
+        assert_eq!(error.actual, Some(Data::Int(108)));
+        assert_eq!(error.path, vec![PathSegment::AllOf(1)]);
+    }
+
+    #[test]
+    fn repeats() {
+        // chunks of (length, type, data), terminated by a chunk of type 0
+        let chunk = {
+            use prelude::*;
+            record((
+                ("length", U8.store("length")),
+                ("type", U8.store("type")),
+                ("data", array_of(U8, getvar("length"))),
+            ))
+        };
+        let parser = prelude::repeat_until(chunk, prelude::getvar("type").equals(0));
+        let bytes = render(((2u8, 1u8, 7u8, 8u8), (0u8, 0u8), 99u8));
+        let data = parser.parse(&bytes).unwrap();
+        assert_eq!(data.seq().unwrap().len(), 2);
+        assert_eq!(parser.render(&data).unwrap(), &bytes[..bytes.len() - 1]);
+
+        let parser = prelude::repeat_eof(prelude::U8).max_repeat(2);
+        assert!(parser.parse(&[1, 2]).is_ok());
+        let error = parser.parse(&[1, 2, 3]).unwrap_err();
+        assert_eq!(error.offset, Some(2));
+        assert_eq!(error.path, vec![PathSegment::Array(2)]);
+    }
+
+    #[test]
+    fn sized() {
+        // RIFF style chunks: a tag, a length and then the data,
+        // of which only the first field is known here
+        let chunk = {
+            use prelude::*;
+            record((
+                ("tag", magic(b"fmt ")),
+                ("length", U32.store("length")),
+                ("data", sized(getvar("length"), U16)),
+            ))
+        };
+        let parser = prelude::all_of((chunk, prelude::U8));
+        let bytes = render((b'f', b'm', b't', b' ', 4u32, 7u16, 0u16, 9u8));
+        let data = parser.parse(&bytes).unwrap();
+        assert_eq!(data.seq().unwrap()[0].get("data"), Some(&Data::Int(7)));
+        assert_eq!(data.seq().unwrap()[1], Data::Int(9));
+        assert_eq!(parser.render(&data).unwrap(), bytes);
+
+        // the child cannot read past the limit
+        let parser = prelude::sized(1, prelude::U16);
+        let error = parser.parse(&[1, 2, 3]).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::UnexpectedEof);
+        assert_eq!(error.offset, Some(0));
+
+        // but repeating until the end stops at the limit
+        let parser = prelude::all_of((
+            prelude::sized(2, prelude::repeat_eof(prelude::U8)),
+            prelude::U8,
+        ));
+        assert_eq!(
+            parser.parse(&[1, 2, 3]).unwrap().seq().unwrap()[1],
+            Data::Int(3)
+        );
+
+        let parser = prelude::sized_exact(3, prelude::U16);
+        assert!(parser.parse(&[1, 2, 3]).is_err());
+        assert!(parser.render(&Data::Int(1)).is_err());
+        assert!(prelude::sized(4, prelude::U16).parse(&[1, 2, 3]).is_err());
+
+        // nor seek back before its start
+        let parser = prelude::all_of((
+            prelude::U8,
+            prelude::U8,
+            prelude::sized(2, prelude::seek(0)),
+        ));
+        let error = parser.parse(&[1, 2, 3, 4]).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::UnexpectedEof);
+        assert_eq!(error.path, vec![PathSegment::AllOf(2)]);
+        let parser = prelude::sized(2, prelude::all_of((prelude::U8, prelude::seek(0))));
+        assert!(parser.parse(&[1, 2, 3]).is_ok());
+    }
+
+    #[test]
+    fn offsets() {
+        use prelude::*;
+
+        // a table of two pointers to strings
+        let pointer = all_of((U8.store("ptr"), at(getvar("ptr"), CSTR)));
+        let parser = array_of(pointer, 2);
+        let data = parser.parse(b"\x02\x04a\x00b\x00").unwrap();
+        let strings: Vec<_> = data
+            .seq()
+            .unwrap()
+            .iter()
+            .map(|x| x.seq().unwrap()[1].clone())
+            .collect();
+        assert_eq!(strings, vec![Data::from("a"), "b".into()]);
+        assert!(parser.render(&data).is_err());
+        let error = parser.parse(&[9, 0]).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::UnexpectedEof);
+        assert_eq!(
+            error.path,
+            vec![PathSegment::Array(0), PathSegment::AllOf(1)]
+        );
+
+        // padding before a field at a fixed offset
+        let parser = all_of((U8, skip(1), seek(4), U8));
+        let data = parser.parse(&[1, 2, 3, 4, 5]).unwrap();
+        assert_eq!(data.seq().unwrap()[1], Data::fbytes(vec![2]));
+        assert_eq!(parser.render(&data).unwrap(), &[1, 2, 0, 0, 5]);
+        let data = Data::fseq(vec![1.into(), Data::Int(0), 4.into(), 5.into()]);
+        assert_eq!(parser.render(&data).unwrap(), &[1, 0, 0, 0, 5]);
+    }
+
+    #[test]
+    fn bits() {
+        use prelude::*;
+
+        // the first bytes of an IPv4 header
+        let parser = record((
+            ("version", bits(4)),
+            ("ihl", bits(4)),
+            ("dscp", bits(6)),
+            ("ecn", bits(2)),
+            ("length", BE_U16),
+            (
+                "flags",
+                bitfield(
+                    BE_U16,
+                    &[("reserved", 1), ("df", 1), ("mf", 1), ("offset", 13)],
+                ),
+            ),
+        ));
+        let bytes = [0x45, 0x02, 0x00, 0x54, 0x40, 0x01];
+        let data = parser.parse(&bytes).unwrap();
+        assert_eq!(data.get("version"), Some(&Data::Int(4)));
+        assert_eq!(data.get("ihl"), Some(&Data::Int(5)));
+        assert_eq!(data.get("ecn"), Some(&Data::Int(2)));
+        let flags = data.get("flags").unwrap();
+        assert_eq!(flags.get("df"), Some(&Data::Int(1)));
+        assert_eq!(flags.get("offset"), Some(&Data::Int(1)));
+        assert_eq!(parser.render(&data).unwrap(), bytes);
+
+        // DEFLATE style, least significant bit first,
+        // with byte patterns skipping to the next whole byte
+        let parser = all_of((bits_lsb(1), bits_lsb(2), U8, bits_lsb(9)));
+        let bytes = [0b0000_0101, 7, 0xFF, 0x01];
+        let data = parser.parse(&bytes).unwrap();
+        assert_eq!(data, ints(&[1, 2, 7, 0x1FF]));
+        assert_eq!(parser.render(&data).unwrap(), bytes);
+
+        // alternatives go back to the same bit
+        let parser = all_of((bits(4), any_of((all_of((bits(4), magic(&[1]))), bits(4)))));
+        assert_eq!(
+            parser.parse(&[0x12, 3]).unwrap().seq().unwrap()[1],
+            Data::Int(2)
+        );
+        let data = parser.parse(&[0x12, 3]).unwrap();
+        assert_eq!(parser.render(&data).unwrap(), &[0x12]);
+
+        assert_eq!(bool_bit().parse(&[0x80]).unwrap(), Data::Int(1));
+        let error = bits(9).parse(&[1]).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::UnexpectedEof);
+        assert!(bits(3).render(&Data::Int(8)).is_err());
+
+        // a partly read byte counts towards a size
+        assert_eq!(sized_exact(1, bits(4)).parse(&[0x12]), Ok(Data::Int(1)));
+        assert!(sized_exact(2, bits(4)).parse(&[0x12, 0]).is_err());
+        assert!(bitfield(U8, &[("a", 4), ("b", 4)])
+            .render(&Data::fmap(vec![("a", Data::Int(1))]))
+            .is_err());
+
+        // fields that do not fill the integer start at its top bit
+        let parser = bitfield(BE_U16, &[("flags", 3)]);
+        let data = parser.parse(&[0xE0, 0x07]).unwrap();
+        assert_eq!(data, Data::fmap(vec![("flags", Data::Int(7))]));
+        assert_eq!(parser.render(&data).unwrap(), [0xE0, 0]);
+        let data = parser.parse(&[0x00, 0x07]).unwrap();
+        assert_eq!(data, Data::fmap(vec![("flags", Data::Int(0))]));
+        let data = bitfield(I8, &[("sign", 1)]).parse(&[0x80]).unwrap();
+        assert_eq!(data, Data::fmap(vec![("sign", Data::Int(1))]));
+
+        // spans cover every byte that bits came from
+        let (_, spans) = all_of((bits(4), bits(8)))
+            .parse_spans(&[0x12, 0x34])
+            .unwrap();
+        let ranges: Vec<_> = spans.iter().map(|span| (span.start, span.end)).collect();
+        assert_eq!(ranges, [(0, 1), (0, 2)]);
+    }
+
+    #[test]
+    fn cstr() {
+        use prelude::*;
+
+        // the NUL is consumed, and rendered again
+        let parser = all_of((CSTR, U8));
+        let data = parser.parse(b"ab\0\x05").unwrap();
+        assert_eq!(data, Data::fseq(vec!["ab".into(), Data::Int(5)]));
+        assert_eq!(parser.render(&data).unwrap(), b"ab\0\x05");
+        assert_eq!(
+            CSTR.parse(b"ab").unwrap_err().kind,
+            ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn wide_ints() {
+        use prelude::*;
+
+        let bytes = [0xFF; 8];
+        assert_eq!(U64.parse(&bytes).unwrap(), Data::UInt(u64::MAX));
+        assert_eq!(I64.parse(&bytes).unwrap(), Data::Int(-1));
+        assert_eq!(U64.render(&Data::UInt(u64::MAX)).unwrap(), bytes);
+        assert!(U64.render(&Data::Int(-1)).is_err());
+        assert!(I64.render(&Data::UInt(u64::MAX)).is_err());
+        for pattern in &[U8, ULEB128] {
+            let error = pattern.render(&Data::Int(-1)).unwrap_err();
+            assert!(error.message.starts_with("Value -1 does not fit in u"));
+        }
+        assert_eq!(Data::Int(-1).u64(), None);
+        let error = I8.flags(vec![(1, "A")], Unknown::Keep).parse(&[0xFF]);
+        assert_eq!(error.unwrap_err().kind, ErrorKind::TypeMismatch);
+        assert_eq!(
+            le_magic_u64(u64::MAX).parse(&bytes).unwrap(),
+            Data::UInt(u64::MAX)
+        );
+
+        let mut bytes = [0u8; 16];
+        bytes[0] = 0x80;
+        assert_eq!(BE_U128.parse(&bytes).unwrap(), Data::fu128(1 << 127));
+        assert_eq!(BE_I128.parse(&bytes).unwrap(), Data::fi128(i128::MIN));
+        assert_eq!(LE_U128.parse(&bytes).unwrap(), Data::Int(0x80));
+        for pattern in &[BE_U128, BE_I128] {
+            let data = pattern.parse(&bytes).unwrap();
+            assert_eq!(pattern.render(&data).unwrap(), bytes);
+        }
+
+        // a huge length is an error rather than a negative number
+        let parser = all_of((U64.store("n"), array_of(U8, getvar("n"))));
+        let error = parser.parse(&[0xFF; 9]).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::TypeMismatch);
+
+        // expressions work on all integers
+        let parser = array_of(U8, (getvar("n") - u64::MAX as i64).equals(0));
+        assert!(parser.parse(&[]).is_err());
+        let parser = all_of((
+            U64.store("n"),
+            array_of(
+                U8,
+                getvar("n")
+                    .gt(i64::MAX)
+                    .if_then_else(getvar("n") / (1i64 << 62), 0),
+            ),
+        ));
+        let data = parser
+            .parse(&[0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 1, 2, 3])
+            .unwrap();
+        assert_eq!(data.seq().unwrap()[1].seq().unwrap().len(), 3);
+
+        // and so does add, past the i64 and u64 boundaries
+        let pattern = U64.add(1);
+        let data = pattern.parse(&[0xFF; 8]).unwrap();
+        assert_eq!(data, Data::fu128(u64::MAX as u128 + 1));
+        assert_eq!(pattern.render(&data).unwrap(), [0xFF; 8]);
+        let bytes = i64::MAX.to_le_bytes();
+        assert_eq!(I64.add(1).parse(&bytes), Ok(Data::UInt(1 << 63)));
+        assert_eq!(I64.add(-1).parse(&[0; 8]), Ok(Data::Int(-1)));
+        let error = LE_I128.add(1).parse(&i128::MAX.to_le_bytes()).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Overflow);
+    }
+
+    #[test]
+    #[should_panic(expected = "terminator must not be empty")]
+    fn empty_terminator() {
+        prelude::string_until(b"");
+    }
+
+    #[test]
+    fn strings() {
+        use prelude::*;
+
+        // a FAT directory entry starts with a space padded 8.3 name
+        let parser = record((
+            ("name", fixed_string(8).padding(b" ")),
+            ("ext", fixed_string(3).padding(b" ")),
+        ));
+        let data = parser.parse(b"README  TXT").unwrap();
+        assert_eq!(data.get("name"), Some(&"README".into()));
+        assert_eq!(data.get("ext"), Some(&"TXT".into()));
+        assert_eq!(parser.render(&data).unwrap(), b"README  TXT");
+        assert!(parser
+            .render(&Data::fmap(vec![
+                ("name", "TOO_LONG_".into()),
+                ("ext", "".into())
+            ]))
+            .is_err());
+
+        let parser = all_of((pascal_string(BE_U16), string_until(b"\r\n"), CSTR));
+        let bytes = b"\x00\x02hiline\r\nc\x00";
+        let data = parser.parse(bytes).unwrap();
+        assert_eq!(
+            data,
+            Data::fseq(vec!["hi".into(), "line".into(), "c".into()])
+        );
+        assert_eq!(parser.render(&data).unwrap(), bytes);
+
+        let error = string_until(b";").parse(b"abc").unwrap_err();
+        assert_eq!(error.kind, ErrorKind::UnexpectedEof);
+        assert_eq!(error.expected, Some(Data::from(&b";"[..])));
+
+        // invalid UTF-8
+        let bytes = b"\x02\xFF\xFE";
+        assert_eq!(
+            pascal_string(U8).parse(bytes).unwrap_err().kind,
+            ErrorKind::Utf8
+        );
+        let parser = pascal_string(U8).lenient();
+        let data = parser.parse(bytes).unwrap();
+        assert_eq!(data, Data::fbytes(vec![0xFF, 0xFE]));
+        assert_eq!(parser.render(&data).unwrap(), bytes);
+        assert_eq!(
+            CSTR.lenient().parse(b"\xFF\x00").unwrap(),
+            Data::fbytes(vec![0xFF])
+        );
+    }
+
+    #[test]
+    fn encodings() {
+        use prelude::*;
+
+        // the NUL terminator is only found on a code unit boundary,
+        // so the 0x00 in "A" and the 0x01 0x00 of U+0100 don't end it
+        let parser = CSTR.encoding(Encoding::Utf16Le);
+        let bytes = b"A\x00\x00\x01\x00\x00";
+        let data = parser.parse(bytes).unwrap();
+        assert_eq!(data, Data::from("A\u{100}"));
+        assert_eq!(parser.render(&data).unwrap(), bytes);
+
+        // UTF-16 with a byte order mark, NUL padded to a fixed size
+        let parser = fixed_string(8).encoding(Encoding::Utf16);
+        let data = parser.parse(b"\xFE\xFF\x00h\x00i\x00\x00").unwrap();
+        assert_eq!(data, Data::from("hi"));
+        assert_eq!(parser.render(&data).unwrap(), b"\xFF\xFEh\x00i\x00\x00\x00");
+
+        let parser = pascal_string(U8).encoding(Encoding::Latin1);
+        let data = parser.parse(b"\x04caf\xE9").unwrap();
+        assert_eq!(data, Data::from("caf\u{E9}"));
+        assert_eq!(parser.render(&data).unwrap(), b"\x04caf\xE9");
+        assert!(parser.render(&Data::from("\u{20AC}")).is_err());
+
+        let parser = pascal_string(U8).encoding(Encoding::Ascii);
+        let error = parser.parse(b"\x01\xE9").unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Encoding);
+        assert_eq!(
+            parser.lenient().parse(b"\x01\xE9").unwrap(),
+            Data::fbytes(vec![0xE9])
+        );
+
+        let parser = fixed_string(4).encoding(Encoding::Windows1252);
+        let data = parser.parse(b"\x80\x99\x00\x00").unwrap();
+        assert_eq!(data, Data::from("\u{20AC}\u{2122}"));
+        assert_eq!(parser.render(&data).unwrap(), b"\x80\x99\x00\x00");
+    }
+
+    #[test]
+    fn endian() {
+        use prelude::*;
+
+        // a TIFF header, in either byte order
+        let parser = all_of((
+            byte_order(b"II", b"MM").store("order"),
+            with_endian(
+                getvar("order"),
+                record((
+                    ("magic", u16()),
+                    ("ifd", u32()),
+                    ("bytes", all_of((BE_U16, LE_U16))),
+                )),
+            ),
+        ));
+        let little = b"II\x2A\x00\x08\x00\x00\x00\x00\x01\x01\x00";
+        let big = b"MM\x00\x2A\x00\x00\x00\x08\x00\x01\x01\x00";
+        for bytes in &[little, big] {
+            let data = parser.parse(*bytes).unwrap();
+            let header = &data.seq().unwrap()[1];
+            assert_eq!(header.get("magic"), Some(&Data::Int(42)));
+            assert_eq!(header.get("ifd"), Some(&Data::Int(8)));
+
+            // fixed byte order patterns are unaffected
+            assert_eq!(
+                header.get("bytes"),
+                Some(&Data::fseq(vec![Data::Int(1), Data::Int(1)]))
+            );
+            assert_eq!(parser.render(&data).unwrap(), *bytes);
+        }
+        assert_eq!(
+            parser.parse(big).unwrap().seq().unwrap()[0],
+            Data::from(Endian::Big)
+        );
+        assert!(parser.parse(b"IM\x2A\x00").is_err());
+
+        // little endian outside of any with_endian, and restored after one
+        let parser = all_of((with_endian(Endian::Big, u16()), u16()));
+        assert_eq!(
+            parser.parse(b"\x01\x02\x01\x02").unwrap(),
+            Data::fseq(vec![Data::Int(0x0102), Data::Int(0x0201)])
+        );
+        let error = with_endian("middle", u16()).parse(b"\x00\x00").unwrap_err();
+        assert_eq!(error.kind, ErrorKind::TypeMismatch);
+    }
+
+    #[test]
+    fn lookahead() {
+        use prelude::*;
+
+        // the tag byte is looked at to pick a record,
+        // and then parsed again as part of it
+        let message = any_of((
+            all_of((peek(magic(&[1])), record((("tag", U8), ("value", U8))))),
+            all_of((
+                not(magic(&[1])),
+                record((("tag", U8), ("text", pascal_string(U8)))),
+            )),
+        ));
+        let parser = all_of((repeat_until(message, offset().ge(4)), eof()));
+        let bytes = [1, 7, 2, 0];
+        let data = parser.parse(&bytes).unwrap();
+        let messages = data.seq().unwrap()[0].seq().unwrap().clone();
+        assert_eq!(
+            messages[0].seq().unwrap()[1].get("value"),
+            Some(&Data::Int(7))
+        );
+        assert_eq!(messages[1].seq().unwrap()[1].get("text"), Some(&"".into()));
+        assert_eq!(parser.render(&data).unwrap(), bytes);
+        let error = parser.parse(&[1, 7, 2, 0, 9]).unwrap_err();
+        assert_eq!(error.offset, Some(4));
+
+        // lookahead leaves no spans behind
+        let (_, spans) = all_of((peek(U8), U8)).parse_spans(&[5]).unwrap();
+        assert_eq!(spans.len(), 1);
+
+        let parser = all_of((U8, rest()));
+        let data = parser.parse(&[1, 2, 3]).unwrap();
+        assert_eq!(data.seq().unwrap()[1], Data::fbytes(vec![2, 3]));
+        assert_eq!(parser.render(&data).unwrap(), [1, 2, 3]);
+        assert_eq!(
+            sized(1, rest()).parse(&[1, 2]).unwrap(),
+            Data::fbytes(vec![1])
+        );
+
+        // with partial input, there might be more to come
+        assert!(eof().parse_partial(&[]).unwrap_err().is_incomplete());
+        assert!(rest().parse_partial(&[1]).unwrap_err().is_incomplete());
+        assert!(not(BE_U16).parse_partial(&[1]).unwrap_err().is_incomplete());
+    }
+
+    #[test]
+    fn checks() {
+        use prelude::*;
+
+        let header = record((
+            ("magic", magic(b"BM")),
+            ("file_size", LE_U32.expect_eq(input_len())),
+            ("planes", LE_U16.one_of(vec![1])),
+            ("bpp", LE_U16.one_of(vec![1, 4, 8, 16, 24, 32])),
+            ("width", LE_I32.in_range(1, 0x10000)),
+            (
+                "flags",
+                U8.assert(|_, data| data.i64() != Some(0xFF), "Reserved flags"),
+            ),
+        ));
+        let mut bytes = render(((b'B', b'M'), 15u32, 1u16, 24u16, 640i32, 0u8));
+        header.parse(&bytes).unwrap();
+
+        let check = |bytes: &[u8], field: &str, offset: usize| {
+            let error = header.parse(bytes).unwrap_err();
+            assert_eq!(error.kind, ErrorKind::Validation);
+            assert_eq!(error.path, vec![PathSegment::Field(field.into())]);
+            assert_eq!(error.offset, Some(offset));
+            error
+        };
+        bytes.push(0);
+        let error = check(&bytes, "file_size", 2);
+        assert_eq!(error.expected, Some(Data::Int(16)));
+        assert_eq!(error.actual, Some(Data::Int(15)));
+        bytes.pop();
+
+        bytes[8] = 3;
+        check(&bytes, "bpp", 8);
+        bytes[8] = 24;
+        bytes[13] = 0x80; // negative width
+        let error = check(&bytes, "width", 10);
+        assert_eq!(
+            error.expected,
+            Some(Data::fseq(vec![Data::Int(1), Data::Int(0x10000)]))
+        );
+        bytes[13] = 0;
+        bytes[14] = 0xFF;
+        let error = check(&bytes, "flags", 14);
+        assert_eq!(error.message, "Reserved flags");
+
+        // only parsing checks
+        let data = Data::fmap(vec![
+            ("magic", Data::fbytes(b"BM".to_vec())),
+            ("file_size", Data::Int(0)),
+            ("planes", Data::Int(2)),
+            ("bpp", Data::Int(3)),
+            ("width", Data::Int(0)),
+            ("flags", Data::Int(0xFF)),
+        ]);
+        assert!(header.render(&data).is_ok());
+    }
+
+    #[test]
+    fn symbols() {
+        use prelude::*;
+
+        let compression = |unknown| {
+            LE_U32.store("compression").enumerate(
+                vec![
+                    (0, "BI_RGB"),
+                    (1, "BI_RLE8"),
+                    (2, "BI_RLE4"),
+                    (3, "BI_BITFIELDS"),
+                ],
+                unknown,
+            )
+        };
+        let parser = all_of((
+            compression(Unknown::Error),
+            array_of(U8, getvar("compression")),
+        ));
+        let data = parser.parse(&[2, 0, 0, 0, 9, 9]).unwrap();
+        assert_eq!(data.seq().unwrap()[0], "BI_RLE4".into());
+        assert_eq!(parser.render(&data).unwrap(), [2, 0, 0, 0, 9, 9]);
+
+        let error = parser.parse(&[7, 0, 0, 0]).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Validation);
+        assert_eq!(error.actual, Some(Data::Int(7)));
+        assert_eq!(
+            compression(Unknown::Keep).parse(&[7, 0, 0, 0]),
+            Ok(Data::Int(7))
+        );
+        let parser = compression(Unknown::Tag);
+        let data = parser.parse(&[7, 0, 0, 0]).unwrap();
+        assert_eq!(data, Data::fseq(vec!["Unknown".into(), Data::Int(7)]));
+        assert_eq!(parser.render(&data).unwrap(), [7, 0, 0, 0]);
+        assert_eq!(parser.render(&Data::Int(1)).unwrap(), [1, 0, 0, 0]);
+        assert!(parser.render(&"BI_JPEG".into()).is_err());
+
+        // ELF segment permissions, with a multi-bit mask
+        let parser = LE_U32.flags(
+            vec![
+                (1, "X"),
+                (2, "W"),
+                (4, "R"),
+                (6, "RW"),
+                (0xF0000000, "MASKPROC"),
+            ],
+            Unknown::Error,
+        );
+        let data = parser.parse(&[6, 0, 0, 0]).unwrap();
+        assert_eq!(data, Data::fseq(vec!["W".into(), "R".into(), "RW".into()]));
+        assert_eq!(parser.render(&data).unwrap(), [6, 0, 0, 0]);
+        assert_eq!(parser.parse(&[0, 0, 0, 0]).unwrap(), Data::fseq(vec![]));
+        assert_eq!(
+            parser.parse(&[0, 0, 0, 0x10]).unwrap_err().actual,
+            Some(Data::Int(0x10000000))
+        );
+    }
+
+    #[test]
+    fn checksums() {
+        use prelude::*;
+
+        // a PNG chunk's CRC covers its type and data
+        let chunk = record((
+            ("len", BE_U32.store("len")),
+            ("type", fixed_string(4)),
+            ("data", array_of(U8, getvar("len"))),
+            (
+                "crc",
+                checksum(
+                    Checksum::Crc32,
+                    (offset() - getvar("len") - 4, offset()),
+                    BE_U32,
+                ),
+            ),
+        ));
+        let mut bytes = b"\0\0\0\0IEND\xAE\x42\x60\x82".to_vec();
+        let data = chunk.parse(&bytes).unwrap();
+        assert_eq!(data.get("crc"), Some(&Data::Int(0xAE426082)));
+        bytes[11] = 0x83;
+        let error = chunk.parse(&bytes).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::ChecksumMismatch);
+        assert_eq!(error.path, vec![PathSegment::Field("crc".into())]);
+        assert_eq!(error.offset, Some(8));
+        assert_eq!(error.expected, Some(Data::Int(0xAE426082)));
+        assert_eq!(error.actual, Some(Data::Int(0xAE426083)));
+
+        // rendering fills in the right value
+        let data = Data::fmap(vec![
+            ("len", Data::Int(2)),
+            ("type", "tEXt".into()),
+            (
+                "data",
+                Data::fseq(vec![Data::Int(b'h' as i64), Data::Int(b'i' as i64)]),
+            ),
+            ("crc", Data::Int(0)),
+        ]);
+        let bytes = chunk.render(&data).unwrap();
+        assert_eq!(
+            &bytes[10..],
+            &Checksum::Crc32.compute(b"tEXthi").to_be_bytes()[4..]
+        );
+        assert_eq!(
+            chunk.parse(&bytes).unwrap().get("type"),
+            Some(&"tEXt".into())
+        );
+
+        // a trailer over the text after a marked offset, past its length byte
+        let parser = all_of((
+            U8,
+            mark("start"),
+            pascal_string(U8),
+            checksum(Checksum::Adler32, (getvar("start") + 1, offset()), BE_U32),
+        ));
+        let bytes = b"\x09\x09Wikipedia\x11\xE6\x03\x98";
+        assert_eq!(
+            parser.parse(bytes).unwrap().seq().unwrap()[3],
+            Data::Int(0x11E60398)
+        );
+        assert_eq!(parser.render(&parser.parse(bytes).unwrap()).unwrap(), bytes);
+
+        let error = checksum(Checksum::Xor8, (0, 10), U8)
+            .parse(&[0])
+            .unwrap_err();
+        assert_eq!(error.kind, ErrorKind::UnexpectedEof);
+        let error = checksum(Checksum::Xor8, (0, 0), magic(b"x"))
+            .parse(b"x")
+            .unwrap_err();
+        assert_eq!(error.kind, ErrorKind::TypeMismatch);
+        assert!(checksum(Checksum::Xor8, (0, 1), U8)
+            .render(&Data::Int(0))
+            .is_err());
+    }
 }
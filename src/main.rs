@@ -0,0 +1,264 @@
+//! Command line front end: parses a file with a pattern and prints the result
+use dbin::Data;
+use dbin::Pattern;
+use dbin::Span;
+use std::fmt::Write;
+use std::process::exit;
+
+const USAGE: &str = "\
+usage: dbin [--format json|tree|hexdump] (SPEC | -e PATTERN) INPUT
+
+SPEC is either the name of a built-in sample (bitmap), or a file
+containing a pattern in the textual pattern language.
+-e takes the pattern itself on the command line instead.";
+
+enum Format {
+    Json,
+    Tree,
+    Hexdump,
+}
+
+fn main() {
+    let mut format = Format::Tree;
+    let mut inline = None;
+    let mut positional = Vec::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-f" | "--format" => {
+                format = match args.next().as_deref() {
+                    Some("json") => Format::Json,
+                    Some("tree") => Format::Tree,
+                    Some("hexdump") | Some("hex") => Format::Hexdump,
+                    _ => usage(),
+                }
+            }
+            "-e" => inline = Some(args.next().unwrap_or_else(|| usage())),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ => positional.push(arg),
+        }
+    }
+
+    let (pattern, input) = match (inline, positional.as_slice()) {
+        (Some(src), [input]) => (compile(&src, "-e"), input),
+        (None, [spec, input]) => (load_spec(spec), input),
+        _ => usage(),
+    };
+    let bytes = std::fs::read(input).unwrap_or_else(|e| fail(&format!("{}: {}", input, e)));
+
+    let (data, spans) = match pattern.parse_spans(&bytes) {
+        Ok(result) => result,
+        Err(error) => {
+            eprintln!("{}: {}", input, error);
+            if let Some(offset) = error.offset {
+                let line = offset / 16 * 16;
+                let end = std::cmp::min(line + 16, bytes.len());
+                eprint!("{}", hexdump_line(line, &bytes[line..end], ""));
+            }
+            exit(1);
+        }
+    };
+
+    let out = match format {
+        Format::Json => {
+            let mut out = String::new();
+            json(&mut out, &data);
+            out + "\n"
+        }
+        Format::Tree => {
+            let mut out = String::new();
+            tree(&mut out, &data, 0);
+            out
+        }
+        Format::Hexdump => hexdump(&bytes, &spans),
+    };
+    // ignore errors such as a closed pipe (e.g. dbin ... | head)
+    let _ = std::io::Write::write_all(&mut std::io::stdout(), out.as_bytes());
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    exit(2)
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("dbin: {}", message);
+    exit(2)
+}
+
+fn load_spec(spec: &str) -> Pattern {
+    match spec {
+        "bitmap" => dbin::samples::bitmap::pattern(),
+        path => {
+            let src =
+                std::fs::read_to_string(path).unwrap_or_else(|e| fail(&format!("{}: {}", path, e)));
+            compile(&src, path)
+        }
+    }
+}
+
+fn compile(src: &str, name: &str) -> Pattern {
+    Pattern::compile(src).unwrap_or_else(|e| fail(&format!("{}: {}", name, e)))
+}
+
+fn json(out: &mut String, data: &Data) {
+    match data {
+        Data::Int(_) | Data::UInt(_) | Data::Int128(_) | Data::UInt128(_) => {
+            write!(out, "{}", data).unwrap()
+        }
+        Data::Float(x) if x.is_finite() => write!(out, "{}", x).unwrap(),
+        Data::Float(_) => out.push_str("null"),
+        Data::Bytes(bytes) => write!(out, "{:?}", bytes).unwrap(),
+        Data::String(s) => json_string(out, s),
+        Data::Seq(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                json(out, item);
+            }
+            out.push(']');
+        }
+        Data::Map(pairs) => {
+            out.push('{');
+            for (i, (name, value)) in pairs.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                json_string(out, name);
+                out.push(':');
+                json(out, value);
+            }
+            out.push('}');
+        }
+    }
+}
+
+fn json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn tree(out: &mut String, data: &Data, depth: usize) {
+    match data {
+        Data::Seq(items) => {
+            for (i, item) in items.iter().enumerate() {
+                tree_entry(out, &i.to_string(), item, depth);
+            }
+        }
+        Data::Map(pairs) => {
+            for (name, value) in pairs.iter() {
+                tree_entry(out, name, value, depth);
+            }
+        }
+        _ => writeln!(out, "{}{}", "  ".repeat(depth), data).unwrap(),
+    }
+}
+
+fn tree_entry(out: &mut String, name: &str, data: &Data, depth: usize) {
+    let indent = "  ".repeat(depth);
+    match data {
+        Data::Seq(_) | Data::Map(_) => {
+            writeln!(out, "{}{}:", indent, name).unwrap();
+            tree(out, data, depth + 1);
+        }
+        _ => writeln!(out, "{}{}: {}", indent, name, data).unwrap(),
+    }
+}
+
+/// One line per primitive value (wrapped every 16 bytes),
+/// with any bytes not covered by a value shown unannotated
+fn hexdump(bytes: &[u8], spans: &[Span]) -> String {
+    let mut out = String::new();
+    let mut pos = 0;
+    let mut spans: Vec<_> = spans.iter().collect();
+    spans.sort_by_key(|span| span.start);
+    for span in spans {
+        if span.start < pos {
+            // overlaps what was already shown
+            continue;
+        }
+        hexdump_range(&mut out, bytes, pos, span.start, "");
+        let note = match &span.label {
+            Some(Data::String(label)) => format!("{} = {}", label, span.data),
+            Some(label) => format!("{} = {}", label, span.data),
+            None => format!("{}", span.data),
+        };
+        hexdump_range(&mut out, bytes, span.start, span.end, &note);
+        pos = span.end;
+    }
+    hexdump_range(&mut out, bytes, pos, bytes.len(), "");
+    out
+}
+
+fn hexdump_range(out: &mut String, bytes: &[u8], start: usize, end: usize, note: &str) {
+    for (i, chunk) in bytes[start..end].chunks(16).enumerate() {
+        out.push_str(&hexdump_line(
+            start + i * 16,
+            chunk,
+            if i == 0 { note } else { "" },
+        ));
+    }
+}
+
+fn hexdump_line(offset: usize, chunk: &[u8], note: &str) -> String {
+    let hex: Vec<_> = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{:08x}  {:<48} {}", offset, hex.join(" "), note)
+        .trim_end()
+        .to_owned()
+        + "\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats() {
+        let pattern = Pattern::compile("u8:n [u16be; n] cstr").unwrap();
+        let bytes = [2, 0, 1, 0, 2, b'h', b'"', 0, 0xFF];
+        let (data, spans) = pattern.parse_spans(&bytes).unwrap();
+
+        let mut out = String::new();
+        json(&mut out, &data);
+        assert_eq!(out, r#"[2,[1,2],"h\""]"#);
+
+        let mut out = String::new();
+        tree(&mut out, &data, 0);
+        assert_eq!(out, "0: 2\n1:\n  0: 1\n  1: 2\n2: \"h\\\"\"\n");
+
+        let data = Data::fmap(vec![("a", Data::Int(1)), ("b", vec![Data::Int(2)].into())]);
+        let mut out = String::new();
+        json(&mut out, &data);
+        assert_eq!(out, r#"{"a":1,"b":[2]}"#);
+        let mut out = String::new();
+        tree(&mut out, &data, 0);
+        assert_eq!(out, "a: 1\nb:\n  0: 2\n");
+
+        assert_eq!(
+            hexdump(&bytes, &spans),
+            concat!(
+                "00000000  02                                               2\n",
+                "00000001  00 01                                            1\n",
+                "00000003  00 02                                            2\n",
+                "00000005  68 22 00                                         \"h\\\"\"\n",
+                "00000008  ff\n",
+            )
+        );
+    }
+}
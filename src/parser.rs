@@ -1,15 +1,75 @@
 use crate::err;
+use crate::expr::arith;
+use crate::expr::truthy;
+use crate::reader::Buffered;
+use crate::BitOrder;
+use crate::Check;
+use crate::Checksum;
 use crate::Context;
 use crate::Data;
+use crate::Encoding;
+use crate::Endian;
+use crate::Env;
+use crate::ErrorKind;
 use crate::Expr;
+use crate::Matches;
+use crate::ParseError;
+use crate::PathSegment;
+use crate::Records;
 use crate::Scope;
-use std::fmt::Debug;
+use crate::Var;
+use crate::Varint;
+use std::io::Read;
+use std::io::Seek;
 
-#[derive(Debug, Clone)]
-pub enum ParseError {
-    Other(String),
+/// Where a single primitive value was found in the input
+#[derive(Debug, Clone, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub data: Data,
+
+    /// name of the enclosing Pattern::label, if any
+    pub label: Option<Data>,
+}
+
+/// Default upper bound on the number of elements of a Pattern::Repeat,
+/// to protect against malicious input (see Pattern::max_repeat)
+pub const MAX_REPEAT: usize = 1 << 20;
+
+/// How a Pattern::Repeat decides when to stop
+pub enum Repeat {
+    Until(Expr), // stops after an element for which the Expr is non-zero
+    While(Expr), // parses another element only while the Expr is non-zero
+    Many(usize), // as many elements as match, but at least this many
+    Eof,         // until the end of the input
 }
 
+/// What a Pattern::Sized does when its child does not
+/// use up all of its bytes
+pub enum Fit {
+    Exact, // it's an error
+    Skip,  // the rest is skipped (or zero filled, when rendering)
+}
+
+/// What Pattern::enumerate and Pattern::flags do
+/// with values that they have no name for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unknown {
+    Error, // fail with ErrorKind::Validation
+    Keep,  // result in the Int itself
+    Tag,   // result in ["Unknown", Int], like a Label
+}
+
+/// How a Pattern::Str finds the end of the string
+pub enum StrLen {
+    Prefix(Box<Pattern>), // the length in bytes, parsed right before it
+    Fixed(Expr),          // a fixed number of bytes, usually with padding
+    Until(Vec<u8>),       // up to a terminator, which is consumed too
+}
+
+type MapFn = Box<dyn Fn(&Scope, Data) -> Result<Data, ParseError>>;
+
 pub enum Pattern {
     Exact(Vec<u8>), // expect an exact sequence of bytes
 
@@ -19,15 +79,19 @@ pub enum Pattern {
     LeU16,
     LeU32,
     LeU64,
+    LeU128,
     BeU16,
     BeU32,
     BeU64,
+    BeU128,
     LeI16,
     LeI32,
     LeI64,
+    LeI128,
     BeI16,
     BeI32,
     BeI64,
+    BeI128,
 
     // float types
     LeF32,
@@ -35,122 +99,915 @@ pub enum Pattern {
     BeF32,
     BeF64,
 
-    // null terminated string
+    // integer and float types whose byte order comes from
+    // an enclosing WithEndian (little endian by default)
+    DynU16,
+    DynU32,
+    DynU64,
+    DynU128,
+    DynI16,
+    DynI32,
+    DynI64,
+    DynI128,
+    DynF32,
+    DynF64,
+
+    // variable length integer
+    Varint(Varint),
+
+    // null terminated string. The NUL is consumed too, so that
+    // parsing and rendering agree (it used to be left unread)
     CStr,
 
+    // string, with any of the given padding chars trimmed off the end.
+    // If lenient, invalid text results in Bytes instead of an error
+    Str(StrLen, Encoding, Vec<u8>, bool),
+
+    // unsigned integer of up to 64 bits, which need not be byte aligned.
+    // Any other pattern following it starts at the next whole byte
+    Bits(u32, BitOrder),
+
     // Array, with variable length
     Array(Box<Pattern>, Expr),
 
+    // Array, whose length is determined while parsing
+    // with an upper bound on the number of elements
+    Repeat(Box<Pattern>, Repeat, usize),
+
+    // the child may only read the given number of bytes,
+    // after which parsing continues at the end of those bytes
+    Sized(Box<Pattern>, Expr, Fit),
+
+    // parses the child at an absolute offset into the input,
+    // then continues where it left off
+    At(Box<Pattern>, Expr),
+    // skips the given number of bytes, resulting in the skipped Bytes
+    // (or only their number, when parsing from a reader)
+    Skip(Expr),
+    Seek(Expr), // continues at an absolute offset, resulting in that offset
+
+    // lookahead: Peek results in what the child would parse and
+    // Not succeeds (with an empty Seq) only if the child fails.
+    // Neither consumes any input
+    Peek(Box<Pattern>),
+    Not(Box<Pattern>),
+    Eof,  // succeeds only at the end of the input, resulting in an empty Seq
+    Rest, // all bytes up to the end of the input
+
+    // fails with ErrorKind::Validation unless the value
+    // of the child passes the check. Rendering does not check
+    Validate(Box<Pattern>, Check),
+
+    // parses the child with the byte order the Expr evaluates to
+    // ("little" or "big", see From<Endian> for Data)
+    WithEndian(Expr, Box<Pattern>),
+
+    // parses the stored checksum of the bytes between two absolute
+    // offsets, failing with ErrorKind::ChecksumMismatch unless it is
+    // right. The offsets are evaluated before the stored value is
+    // parsed. Rendering computes it instead, over what is already written
+    Checksum(Checksum, Expr, Expr, Box<Pattern>),
+
     AnyOf(Vec<Pattern>),
-    AllOf(Vec<Pattern>), // results in Seq of patterns
+
+    // deterministic branching on a previously parsed value
+    If(Expr, Box<Pattern>, Box<Pattern>),
+    Switch(Expr, Vec<(Data, Pattern)>, Option<Box<Pattern>>), // with optional default
+    AllOf(Vec<Pattern>),                                      // results in Seq of patterns
+    Record(Vec<(String, Pattern)>),                           // results in Map of named patterns
 
     // pseudo patterns
     // these change the parsed results and parse state,
     // but do not directly modify what sequence of bytes
     // they match
-    Store(Box<Pattern>, i64), // stores the resulting Data into the current scope
-    Map(
-        Box<Pattern>,
-        Box<dyn Fn(&Scope, Data) -> Result<Data, ParseError>>,
-    ),
+    Store(Box<Pattern>, Var), // stores the resulting Data into the current scope
+
+    // variables stored inside are discarded afterwards,
+    // except for the listed ones, which are copied to the parent scope
+    Scoped(Box<Pattern>, Vec<Var>),
+    Map(Box<Pattern>, MapFn, Option<MapFn>), // with optional inverse for rendering
+    Label(Box<Pattern>, Data),               // results in [name, X], and names X in error paths
 }
 
 impl Pattern {
     pub fn map<F: Fn(&Scope, Data) -> Result<Data, ParseError> + 'static>(self, f: F) -> Pattern {
-        Pattern::Map(Box::new(self), Box::new(f))
+        Pattern::Map(Box::new(self), Box::new(f), None)
+    }
+
+    /// Like map, but also accepts the inverse function,
+    /// so that the resulting pattern can still be rendered
+    pub fn bimap<F, G>(self, f: F, inverse: G) -> Pattern
+    where
+        F: Fn(&Scope, Data) -> Result<Data, ParseError> + 'static,
+        G: Fn(&Scope, Data) -> Result<Data, ParseError> + 'static,
+    {
+        Pattern::Map(Box::new(self), Box::new(f), Some(Box::new(inverse)))
     }
     pub fn mapval<D: Into<Data>>(self, d: D) -> Pattern {
         let d = d.into();
         self.map(move |_, _| Ok(d.clone()))
     }
-    pub fn store<K: Into<i64>>(self, key: K) -> Pattern {
+
+    /// Fails unless predicate holds for the parsed value,
+    /// with message as that of the ParseError
+    pub fn assert<F, S>(self, predicate: F, message: S) -> Pattern
+    where
+        F: Fn(&Scope, &Data) -> bool + 'static,
+        S: Into<String>,
+    {
+        Pattern::Validate(
+            self.into(),
+            Check::Assert(Box::new(predicate), message.into()),
+        )
+    }
+
+    /// Fails unless lo <= value <= hi
+    pub fn in_range<L: Into<Data>, H: Into<Data>>(self, lo: L, hi: H) -> Pattern {
+        Pattern::Validate(self.into(), Check::InRange(lo.into(), hi.into()))
+    }
+
+    /// Fails unless the parsed value is one of values
+    pub fn one_of<D: Into<Data>, I: IntoIterator<Item = D>>(self, values: I) -> Pattern {
+        let values = values.into_iter().map(Into::into).collect();
+        Pattern::Validate(self.into(), Check::OneOf(values))
+    }
+
+    /// Fails unless the parsed value equals what expected
+    /// evaluates to, e.g. input_len() for a stored file size
+    pub fn expect_eq<E: Into<Expr>>(self, expected: E) -> Pattern {
+        Pattern::Validate(self.into(), Check::Equals(expected.into()))
+    }
+
+    /// Maps integers to symbolic names, e.g.
+    /// `.enumerate(vec![(0, "BI_RGB"), (1, "BI_RLE8")], Unknown::Keep)`.
+    /// The raw value can still be stored beforehand, with
+    /// `.store(..).enumerate(..)`. Rendering takes names or Ints
+    pub fn enumerate<S, I>(self, names: I, unknown: Unknown) -> Pattern
+    where
+        S: Into<String>,
+        I: IntoIterator<Item = (i64, S)>,
+    {
+        let names: Vec<(i64, Data)> = names
+            .into_iter()
+            .map(|(value, name)| (value, Data::from(name.into())))
+            .collect();
+        let inverse_names = names.clone();
+        self.bimap(
+            move |_, data| {
+                let value = data.i64();
+                match names.iter().find(|(v, _)| Some(*v) == value) {
+                    Some((_, name)) => Ok(name.clone()),
+                    None => unknown_value(unknown, data),
+                }
+            },
+            move |_, data| match inverse_names.iter().find(|(_, name)| *name == data) {
+                Some((value, _)) => Ok(Data::Int(*value)),
+                None => known_value(data),
+            },
+        )
+    }
+
+    /// Decodes a bitmask into a Seq of the names of the flags
+    /// that are set, e.g. `.flags(vec![(1, "READ"), (2, "WRITE")], ..)`.
+    /// Any bits left over are an unknown value, after the names
+    pub fn flags<S, I>(self, flags: I, unknown: Unknown) -> Pattern
+    where
+        S: Into<String>,
+        I: IntoIterator<Item = (u64, S)>,
+    {
+        let flags: Vec<(u64, Data)> = flags
+            .into_iter()
+            .map(|(mask, name)| (mask, Data::from(name.into())))
+            .collect();
+        let inverse_flags = flags.clone();
+        self.bimap(
+            move |_, data| {
+                let value = match data.u64() {
+                    Some(value) => value,
+                    None => return Err(type_mismatch("u64", data, "flags")),
+                };
+                let mut rest = value;
+                let mut names = Vec::new();
+                for (mask, name) in &flags {
+                    if *mask != 0 && value & mask == *mask {
+                        names.push(name.clone());
+                        rest &= !mask;
+                    }
+                }
+                if rest != 0 {
+                    names.push(unknown_value(unknown, Data::fu64(rest))?);
+                }
+                Ok(names.into())
+            },
+            move |_, data| {
+                let items = match &data {
+                    Data::Seq(items) => items.to_vec(),
+                    _ => vec![data],
+                };
+                let mut value = 0;
+                for item in items {
+                    let bits = match inverse_flags.iter().find(|(_, name)| *name == item) {
+                        Some((mask, _)) => Data::fu64(*mask),
+                        None => known_value(item)?,
+                    };
+                    value |= match bits.u64() {
+                        Some(bits) => bits,
+                        None => return Err(type_mismatch("u64", bits, "flags")),
+                    };
+                }
+                Ok(Data::fu64(value))
+            },
+        )
+    }
+    pub fn store<K: Into<Var>>(self, key: K) -> Pattern {
         Pattern::Store(self.into(), key.into())
     }
+    /// Sets the chars (given as bytes) to be trimmed off the end of a
+    /// string pattern. When rendering a fixed_string, it's padded with
+    /// the first one.
+    /// Panics if this is not a string pattern
+    pub fn padding(self, padding: &[u8]) -> Pattern {
+        match self.into_str() {
+            Pattern::Str(len, encoding, _, lenient) => {
+                Pattern::Str(len, encoding, padding.to_vec(), lenient)
+            }
+            _ => panic!("padding is only for string patterns"),
+        }
+    }
+
+    /// Makes a string pattern result in Bytes on invalid text,
+    /// instead of failing.
+    /// Panics if this is not a string pattern
+    pub fn lenient(self) -> Pattern {
+        match self.into_str() {
+            Pattern::Str(len, encoding, padding, _) => Pattern::Str(len, encoding, padding, true),
+            _ => panic!("lenient is only for string patterns"),
+        }
+    }
+
+    /// Changes the encoding of a string pattern from UTF-8.
+    /// Lengths are still in bytes, but for a cstr the terminating
+    /// NUL becomes a whole code unit (e.g. two bytes for UTF-16).
+    /// Panics if this is not a string pattern
+    pub fn encoding(self, encoding: Encoding) -> Pattern {
+        match self {
+            Pattern::CStr => Pattern::Str(
+                StrLen::Until(vec![0; encoding.unit()]),
+                encoding,
+                vec![],
+                false,
+            ),
+            Pattern::Str(len, _, padding, lenient) => Pattern::Str(len, encoding, padding, lenient),
+            _ => panic!("encoding is only for string patterns"),
+        }
+    }
+
+    /// The fixed byte order pattern that an endian-neutral one
+    /// stands for. Panics for any other pattern
+    pub(crate) fn with_byte_order(&self, endian: Endian) -> Pattern {
+        let little = endian == Endian::Little;
+        match self {
+            Pattern::DynU16 if little => Pattern::LeU16,
+            Pattern::DynU16 => Pattern::BeU16,
+            Pattern::DynU32 if little => Pattern::LeU32,
+            Pattern::DynU32 => Pattern::BeU32,
+            Pattern::DynU64 if little => Pattern::LeU64,
+            Pattern::DynU64 => Pattern::BeU64,
+            Pattern::DynU128 if little => Pattern::LeU128,
+            Pattern::DynU128 => Pattern::BeU128,
+            Pattern::DynI16 if little => Pattern::LeI16,
+            Pattern::DynI16 => Pattern::BeI16,
+            Pattern::DynI32 if little => Pattern::LeI32,
+            Pattern::DynI32 => Pattern::BeI32,
+            Pattern::DynI64 if little => Pattern::LeI64,
+            Pattern::DynI64 => Pattern::BeI64,
+            Pattern::DynI128 if little => Pattern::LeI128,
+            Pattern::DynI128 => Pattern::BeI128,
+            Pattern::DynF32 if little => Pattern::LeF32,
+            Pattern::DynF32 => Pattern::BeF32,
+            Pattern::DynF64 if little => Pattern::LeF64,
+            Pattern::DynF64 => Pattern::BeF64,
+            _ => panic!("with_byte_order is only for endian-neutral patterns"),
+        }
+    }
+
+    /// Width in bits of the integers this pattern results in,
+    /// if that is fixed and at most 64
+    pub(crate) fn bit_width(&self) -> Option<u32> {
+        match self {
+            Pattern::U8 | Pattern::I8 => Some(8),
+            Pattern::LeU16
+            | Pattern::BeU16
+            | Pattern::LeI16
+            | Pattern::BeI16
+            | Pattern::DynU16
+            | Pattern::DynI16 => Some(16),
+            Pattern::LeU32
+            | Pattern::BeU32
+            | Pattern::LeI32
+            | Pattern::BeI32
+            | Pattern::DynU32
+            | Pattern::DynI32 => Some(32),
+            Pattern::LeU64
+            | Pattern::BeU64
+            | Pattern::LeI64
+            | Pattern::BeI64
+            | Pattern::DynU64
+            | Pattern::DynI64 => Some(64),
+            Pattern::Bits(n, _) => Some(*n),
+            Pattern::Store(pat, _) | Pattern::Validate(pat, _) | Pattern::WithEndian(_, pat) => {
+                pat.bit_width()
+            }
+            _ => None,
+        }
+    }
+
+    /// CStr as the equivalent, more general Str
+    fn into_str(self) -> Pattern {
+        match self {
+            Pattern::CStr => Pattern::Str(StrLen::Until(vec![0]), Encoding::Utf8, vec![], false),
+            pattern => pattern,
+        }
+    }
+
+    /// Changes the maximum number of elements of a repeat pattern
+    /// (MAX_REPEAT by default).
+    /// Panics if this is not a repeat pattern
+    pub fn max_repeat(self, n: usize) -> Pattern {
+        match self {
+            Pattern::Repeat(pat, mode, _) => Pattern::Repeat(pat, mode, n),
+            _ => panic!("max_repeat is only for repeat patterns"),
+        }
+    }
     pub fn parse(&self, bytes: &[u8]) -> Result<Data, ParseError> {
         let mut ctx = Context::new(bytes);
         self.parse_ctx(&mut ctx)
     }
-    fn parse_ctx(&self, ctx: &mut Context) -> Result<Data, ParseError> {
+
+    /// Like parse, but also reports where in the input each
+    /// primitive (integer, float, string, magic) value came from.
+    /// Useful for annotated hexdumps
+    pub fn parse_spans(&self, bytes: &[u8]) -> Result<(Data, Vec<Span>), ParseError> {
+        let mut ctx = Context::new(bytes);
+        ctx.spans = Some(Vec::new());
+        let data = self.parse_ctx(&mut ctx)?;
+        Ok((data, ctx.spans.unwrap()))
+    }
+
+    /// Like parse, but starting at the given offset instead of the
+    /// start of the input. Offsets (in errors, and for Pattern::At)
+    /// are still from the start of the input
+    pub fn parse_at(&self, bytes: &[u8], offset: usize) -> Result<Data, ParseError> {
+        Ok(self.match_at(bytes, offset)?.0)
+    }
+
+    /// The first match of this pattern anywhere in the input,
+    /// with the offset it starts at
+    pub fn find(&self, bytes: &[u8]) -> Option<(usize, Data)> {
+        self.find_iter(bytes).next()
+    }
+
+    /// All non-overlapping matches of this pattern in the input,
+    /// from first to last, with the offsets they start at.
+    /// Patterns that start with magic bytes are only tried
+    /// where those bytes are found
+    pub fn find_iter<'p, 'b>(&'p self, bytes: &'b [u8]) -> Matches<'p, 'b> {
+        Matches::new(self, bytes)
+    }
+
+    /// Parses starting at offset, also returning where the match ends
+    pub(crate) fn match_at(
+        &self,
+        bytes: &[u8],
+        offset: usize,
+    ) -> Result<(Data, usize), ParseError> {
+        if offset > bytes.len() {
+            return Err(out_of_bounds(offset, bytes.len()));
+        }
+        let mut ctx = Context::new(bytes);
+        ctx.set_pos(offset);
+        let data = self.parse_ctx(&mut ctx)?;
+        Ok((data, ctx.aligned_pos()))
+    }
+
+    /// Like parse, but for input that may be only the start of
+    /// what is to come, e.g. what has arrived so far from a socket.
+    /// Running out of input fails with ErrorKind::Incomplete instead
+    /// of UnexpectedEof, so that it can be retried with more. On
+    /// success, also returns the number of bytes used.
+    /// Since more input could always follow, patterns that repeat
+    /// until the end of the input never succeed (see StreamParser)
+    pub fn parse_partial(&self, bytes: &[u8]) -> Result<(Data, usize), ParseError> {
+        let mut ctx = Context::new(bytes);
+        ctx.partial = true;
+        let data = self.parse_ctx(&mut ctx)?;
+        Ok((data, ctx.aligned_pos()))
+    }
+
+    /// Like parse, but reads the input from a stream as it is needed,
+    /// instead of all at once. Offsets (in errors, and for Pattern::At)
+    /// are from the start of the stream
+    pub fn parse_reader<R: Read + Seek>(&self, reader: R) -> Result<Data, ParseError> {
+        let mut reader = Buffered::new(reader);
+        let len = reader.len()?;
+        self.parse_ctx(&mut Context::from_reader(&mut reader, len))
+    }
+
+    /// Parses this pattern over and over from a stream, one record
+    /// at a time, until the end of the stream. Iteration stops
+    /// after the first error
+    pub fn iter_records<R: Read + Seek>(&self, reader: R) -> Records<'_, R> {
+        Records::new(self, reader)
+    }
+    pub(crate) fn parse_ctx(&self, ctx: &mut Context) -> Result<Data, ParseError> {
+        let start = ctx.pos();
+        let data = self.parse_inner(ctx).map_err(|e| e.or_offset(ctx.pos()))?;
+        if self.is_primitive() {
+            // a partly read byte is part of the span
+            let end = ctx.aligned_pos();
+            if let Some(spans) = &mut ctx.spans {
+                spans.push(Span {
+                    start,
+                    end,
+                    data: data.clone(),
+                    label: None,
+                });
+            }
+        }
+        Ok(data)
+    }
+    fn is_primitive(&self) -> bool {
+        matches!(
+            self,
+            Pattern::Exact(..)
+                | Pattern::U8
+                | Pattern::I8
+                | Pattern::LeU16
+                | Pattern::LeU32
+                | Pattern::LeU64
+                | Pattern::LeU128
+                | Pattern::BeU16
+                | Pattern::BeU32
+                | Pattern::BeU64
+                | Pattern::BeU128
+                | Pattern::LeI16
+                | Pattern::LeI32
+                | Pattern::LeI64
+                | Pattern::LeI128
+                | Pattern::BeI16
+                | Pattern::BeI32
+                | Pattern::BeI64
+                | Pattern::BeI128
+                | Pattern::LeF32
+                | Pattern::LeF64
+                | Pattern::BeF32
+                | Pattern::BeF64
+                | Pattern::DynU16
+                | Pattern::DynU32
+                | Pattern::DynU64
+                | Pattern::DynU128
+                | Pattern::DynI16
+                | Pattern::DynI32
+                | Pattern::DynI64
+                | Pattern::DynI128
+                | Pattern::DynF32
+                | Pattern::DynF64
+                | Pattern::Varint(..)
+                | Pattern::CStr
+                | Pattern::Str(..)
+                | Pattern::Bits(..)
+        )
+    }
+    fn parse_inner(&self, ctx: &mut Context) -> Result<Data, ParseError> {
         match self {
             Pattern::Exact(bytes) => {
-                let peek = ctx.peek(bytes.len())?;
-                if bytes.as_slice() == peek {
+                // with partial input, a mismatch in what has
+                // arrived so far is already a mismatch
+                let n = match ctx.partial {
+                    true => bytes.len().min(ctx.remaining()),
+                    false => bytes.len(),
+                };
+                let peek = ctx.peek(n)?;
+                if bytes[..n] == *peek {
                     Ok(ctx.read(bytes.len())?.into())
                 } else {
-                    Err(ParseError::Other(format!(
-                        "Expected {:?} but got {:?}",
-                        bytes, peek
-                    )))
-                }
-            }
-            Pattern::U8 => Ok((uint(true, ctx.read(1)?) as i64).into()),
-            Pattern::I8 => Ok((sint(true, ctx.read(1)?) as i64).into()),
-            Pattern::LeU16 => Ok((uint(true, ctx.read(2)?) as i64).into()),
-            Pattern::LeU32 => Ok((uint(true, ctx.read(4)?) as i64).into()),
-            Pattern::LeU64 => Ok((uint(true, ctx.read(8)?) as i64).into()),
-            Pattern::BeU16 => Ok((uint(false, ctx.read(2)?) as i64).into()),
-            Pattern::BeU32 => Ok((uint(false, ctx.read(4)?) as i64).into()),
-            Pattern::BeU64 => Ok((uint(false, ctx.read(8)?) as i64).into()),
-            Pattern::LeI16 => Ok((sint(true, ctx.read(2)?) as i64).into()),
-            Pattern::LeI32 => Ok((sint(true, ctx.read(4)?) as i64).into()),
-            Pattern::LeI64 => Ok((sint(true, ctx.read(8)?) as i64).into()),
-            Pattern::BeI16 => Ok((sint(false, ctx.read(2)?) as i64).into()),
-            Pattern::BeI32 => Ok((sint(false, ctx.read(4)?) as i64).into()),
-            Pattern::BeI64 => Ok((sint(false, ctx.read(8)?) as i64).into()),
+                    Err(
+                        ParseError::new(ErrorKind::MagicMismatch, "Magic bytes did not match")
+                            .with_expected(bytes.as_slice())
+                            .with_actual(peek),
+                    )
+                }
+            }
+            Pattern::U8 => Ok(Data::fu128(uint(true, ctx.read(1)?))),
+            Pattern::I8 => Ok(Data::fi128(sint(true, ctx.read(1)?))),
+            Pattern::LeU16 => Ok(Data::fu128(uint(true, ctx.read(2)?))),
+            Pattern::LeU32 => Ok(Data::fu128(uint(true, ctx.read(4)?))),
+            Pattern::LeU64 => Ok(Data::fu128(uint(true, ctx.read(8)?))),
+            Pattern::LeU128 => Ok(Data::fu128(uint(true, ctx.read(16)?))),
+            Pattern::BeU16 => Ok(Data::fu128(uint(false, ctx.read(2)?))),
+            Pattern::BeU32 => Ok(Data::fu128(uint(false, ctx.read(4)?))),
+            Pattern::BeU64 => Ok(Data::fu128(uint(false, ctx.read(8)?))),
+            Pattern::BeU128 => Ok(Data::fu128(uint(false, ctx.read(16)?))),
+            Pattern::LeI16 => Ok(Data::fi128(sint(true, ctx.read(2)?))),
+            Pattern::LeI32 => Ok(Data::fi128(sint(true, ctx.read(4)?))),
+            Pattern::LeI64 => Ok(Data::fi128(sint(true, ctx.read(8)?))),
+            Pattern::LeI128 => Ok(Data::fi128(sint(true, ctx.read(16)?))),
+            Pattern::BeI16 => Ok(Data::fi128(sint(false, ctx.read(2)?))),
+            Pattern::BeI32 => Ok(Data::fi128(sint(false, ctx.read(4)?))),
+            Pattern::BeI64 => Ok(Data::fi128(sint(false, ctx.read(8)?))),
+            Pattern::BeI128 => Ok(Data::fi128(sint(false, ctx.read(16)?))),
             Pattern::LeF32 => Ok((f32::from_bits(uint(true, ctx.read(4)?) as u32) as f64).into()),
-            Pattern::LeF64 => Ok((f64::from_bits(uint(true, ctx.read(8)?) as u64)).into()),
+            Pattern::LeF64 => Ok(f64::from_bits(uint(true, ctx.read(8)?) as u64).into()),
             Pattern::BeF32 => Ok((f32::from_bits(uint(false, ctx.read(4)?) as u32) as f64).into()),
-            Pattern::BeF64 => Ok((f64::from_bits(uint(false, ctx.read(8)?) as u64)).into()),
+            Pattern::BeF64 => Ok(f64::from_bits(uint(false, ctx.read(8)?) as u64).into()),
+            Pattern::DynU16
+            | Pattern::DynU32
+            | Pattern::DynU64
+            | Pattern::DynU128
+            | Pattern::DynI16
+            | Pattern::DynI32
+            | Pattern::DynI64
+            | Pattern::DynI128
+            | Pattern::DynF32
+            | Pattern::DynF64 => self.with_byte_order(ctx.endian).parse_inner(ctx),
+            Pattern::Varint(kind) => kind.read(ctx),
             Pattern::CStr => {
                 let mut bytes = Vec::new();
                 while ctx.peek(1)?[0] != 0 {
                     bytes.push(ctx.read(1)?[0]);
                 }
-                match std::str::from_utf8(&bytes) {
-                    Ok(s) => Ok(s.into()),
-                    Err(error) => err(format!("{:?}", error)),
-                }
+                ctx.read(1)?; // the terminating NUL
+                decode(&bytes, Encoding::Utf8, &[], false)
             }
-            Pattern::Array(pat, expr) => {
-                let len = match expr.eval(ctx.scope())? {
-                    Data::Int(i) => i as usize,
-                    x => return err(format!("Got non-int for array len ({:?})", x)),
+            Pattern::Str(len, encoding, padding, lenient) => {
+                // the string's length in bytes, and that of
+                // the terminator that follows it, if any
+                let (len, terminator_len) = match len {
+                    StrLen::Prefix(pat) => match pat.parse_ctx(ctx)? {
+                        Data::Int(i) if i >= 0 => (i as usize, 0),
+                        x => return Err(type_mismatch("usize", x, "string length")),
+                    },
+                    StrLen::Fixed(expr) => (eval_offset(ctx, expr, "string length")?, 0),
+                    StrLen::Until(terminator) => {
+                        let mut len = 0;
+                        loop {
+                            match ctx.peek(len + terminator.len()) {
+                                Ok(bytes) if bytes.ends_with(terminator) => break,
+                                Ok(_) => len += encoding.unit(),
+                                Err(error) => {
+                                    return Err(ParseError {
+                                        message: "String terminator not found".into(),
+                                        ..error
+                                    }
+                                    .with_expected(terminator.as_slice()))
+                                }
+                            }
+                        }
+                        (len, terminator.len())
+                    }
                 };
+                let data = decode(ctx.read(len)?, *encoding, padding, *lenient)?;
+                ctx.read(terminator_len)?;
+                Ok(data)
+            }
+            Pattern::Bits(n, order) => Ok(Data::fu64(ctx.read_bits(*n, *order)?)),
+            Pattern::Array(pat, expr) => {
+                let len = eval_offset(ctx, expr, "array len")?;
                 let mut ret = Vec::new();
-                for _ in 0..len {
-                    ret.push(pat.parse_ctx(ctx)?);
+                for i in 0..len {
+                    ret.push(
+                        pat.parse_ctx(ctx)
+                            .map_err(|e| e.within(PathSegment::Array(i)))?,
+                    );
                 }
                 Ok(ret.into())
             }
+            Pattern::Repeat(pat, mode, max) => {
+                let mut ret = Vec::new();
+                loop {
+                    let done = match mode {
+                        Repeat::While(cond) => !truthy(cond.eval(&ctx.env())?)?,
+                        Repeat::Eof if ctx.remaining() == 0 => {
+                            // with more input there might be more elements
+                            if let Some(error) = ctx.incomplete(None) {
+                                return Err(error);
+                            }
+                            true
+                        }
+                        _ => false,
+                    };
+                    if done {
+                        break;
+                    }
+                    if ret.len() == *max {
+                        return Err(ParseError::custom(format!(
+                            "Exceeded the maximum of {} repetitions",
+                            max
+                        ))
+                        .within(PathSegment::Array(ret.len())));
+                    }
+                    let mark = ctx.save();
+                    let i = ret.len();
+                    let val = match (pat.parse_ctx(ctx), mode) {
+                        (Ok(val), _) => val,
+                        (Err(e), Repeat::Many(_)) if !e.is_incomplete() => {
+                            ctx.restore(mark);
+                            break;
+                        }
+                        (Err(e), _) => return Err(e.within(PathSegment::Array(i))),
+                    };
+                    if ctx.save() == mark {
+                        // the element matched no input, so repeating
+                        // it would not get us anywhere
+                        match mode {
+                            Repeat::Many(_) => break,
+                            Repeat::Eof => {
+                                return Err(ParseError::custom(
+                                    "Element matched no input before the end",
+                                )
+                                .within(PathSegment::Array(i)))
+                            }
+                            _ => (),
+                        }
+                    }
+                    ret.push(val);
+                    if let Repeat::Until(cond) = mode {
+                        if truthy(cond.eval(&ctx.env())?)? {
+                            break;
+                        }
+                    }
+                }
+                if let Repeat::Many(min) = mode {
+                    if ret.len() < *min {
+                        return Err(ParseError::custom(format!(
+                            "Expected at least {} repetitions but got {}",
+                            min,
+                            ret.len()
+                        ))
+                        .with_expected(*min as i64)
+                        .with_actual(ret.len() as i64));
+                    }
+                }
+                Ok(ret.into())
+            }
+            Pattern::Sized(pat, expr, fit) => {
+                let size = eval_offset(ctx, expr, "size")?;
+                if size > ctx.remaining() {
+                    return Err(ctx.eof_error(size));
+                }
+                let start = ctx.pos();
+                let end = start + size;
+                let outer = ctx.set_limits(Some((start, end)));
+                let result = pat.parse_ctx(ctx);
+                ctx.set_limits(outer);
+                let val = result?;
+                // a partly read byte counts as used
+                let used = match ctx.aligned_pos().checked_sub(start) {
+                    Some(used) => used,
+                    None => return Err(before_start(ctx.pos(), start)),
+                };
+                if let (Fit::Exact, true) = (fit, used < size) {
+                    return Err(ParseError::custom(format!(
+                        "Expected to use all {} bytes, but only used {}",
+                        size, used
+                    ))
+                    .with_expected(size as i64)
+                    .with_actual(used as i64));
+                }
+                ctx.set_pos(end);
+                Ok(val)
+            }
+            Pattern::At(pat, expr) => {
+                let offset = eval_offset(ctx, expr, "offset")?;
+                if offset > ctx.len() {
+                    let needed = Some(offset - ctx.len());
+                    let error = ctx.incomplete(needed);
+                    return Err(error.unwrap_or_else(|| out_of_bounds(offset, ctx.len())));
+                }
+                let mark = ctx.save();
+                // offsets are into the whole input, so any size
+                // limit does not apply either
+                let outer = ctx.set_limits(None);
+                ctx.set_pos(offset);
+                let result = pat.parse_ctx(ctx);
+                ctx.set_limits(outer);
+                ctx.restore(mark);
+                result
+            }
+            Pattern::Skip(expr) => {
+                let n = eval_offset(ctx, expr, "skip")?;
+                match ctx.skip(n)? {
+                    Some(bytes) => Ok(bytes.into()),
+                    None => Ok(Data::fu64(n as u64)),
+                }
+            }
+            Pattern::Seek(expr) => {
+                let offset = eval_offset(ctx, expr, "offset")?;
+                let end = ctx.pos() + ctx.remaining();
+                if offset > end {
+                    let error = ctx.incomplete(Some(offset - end));
+                    return Err(error.unwrap_or_else(|| out_of_bounds(offset, end)));
+                }
+                if offset < ctx.start() {
+                    return Err(before_start(offset, ctx.start()));
+                }
+                ctx.set_pos(offset);
+                Ok((offset as i64).into())
+            }
+            Pattern::Peek(pat) => {
+                let (result, _) = lookahead(ctx, pat);
+                result
+            }
+            Pattern::Not(pat) => match lookahead(ctx, pat) {
+                (Ok(data), pos) => Err(ParseError::custom(format!(
+                    "Pattern matched up to {} but was expected not to",
+                    pos
+                ))
+                .with_actual(data)),
+                (Err(e), _) if e.is_incomplete() => Err(e),
+                (Err(_), _) => Ok(Data::fseq(vec![])),
+            },
+            Pattern::Eof => {
+                let left = rest_len(ctx);
+                if left > 0 {
+                    return Err(ParseError::custom(format!(
+                        "Expected the end of the input, but {} bytes are left",
+                        left
+                    ))
+                    .with_actual(left as i64));
+                }
+                // unless more input might still follow
+                match ctx.incomplete(None) {
+                    Some(error) => Err(error),
+                    None => Ok(Data::fseq(vec![])),
+                }
+            }
+            Pattern::Rest => {
+                if let Some(error) = ctx.incomplete(None) {
+                    return Err(error);
+                }
+                let n = rest_len(ctx);
+                Ok(ctx.read(n)?.into())
+            }
+            Pattern::Validate(pat, check) => {
+                let start = ctx.pos();
+                let data = pat.parse_ctx(ctx)?;
+                check
+                    .check(&ctx.env(), &data)
+                    .map_err(|e| e.or_offset(start))?;
+                Ok(data)
+            }
+            Pattern::WithEndian(expr, pat) => {
+                let endian = expr.eval(&ctx.env())?;
+                let endian = match Endian::from_data(&endian) {
+                    Some(endian) => endian,
+                    None => return Err(type_mismatch("endian", endian, "byte order")),
+                };
+                let outer = std::mem::replace(&mut ctx.endian, endian);
+                let result = pat.parse_ctx(ctx);
+                ctx.endian = outer;
+                result
+            }
+            Pattern::Checksum(checksum, start, end, pat) => {
+                let start = eval_offset(ctx, start, "checksum start")?;
+                let end = eval_offset(ctx, end, "checksum end")?;
+                if end > ctx.len() {
+                    let error = ctx.incomplete(Some(end - ctx.len()));
+                    return Err(error.unwrap_or_else(|| out_of_bounds(end, ctx.len())));
+                }
+                if start > end {
+                    return Err(ParseError::custom(format!(
+                        "Checksum range {}..{} is backwards",
+                        start, end
+                    )));
+                }
+                let offset = ctx.pos();
+                let stored = pat.parse_ctx(ctx)?;
+                let value = match stored.u64() {
+                    Some(value) => value,
+                    None => return Err(type_mismatch("u64", stored, "checksum")),
+                };
+                let computed = checksum.compute(ctx.range(start, end)?);
+                if value != computed {
+                    return Err(ParseError::new(
+                        ErrorKind::ChecksumMismatch,
+                        format!(
+                            "{:?} of bytes {}..{} is {:#x}, but {:#x} is stored",
+                            checksum, start, end, computed, value
+                        ),
+                    )
+                    .with_offset(offset)
+                    .with_expected(Data::fu64(computed))
+                    .with_actual(stored));
+                }
+                Ok(stored)
+            }
             Pattern::AnyOf(pats) => {
-                let pos = ctx.save();
+                let mark = ctx.save();
                 let mut last = err("Empty 'any-of'");
-                for pat in pats {
-                    last = pat.parse_ctx(ctx);
-                    if last.is_ok() {
-                        return last;
-                    } else {
-                        ctx.restore(pos);
+                for (i, pat) in pats.iter().enumerate() {
+                    last = pat
+                        .parse_ctx(ctx)
+                        .map_err(|e| e.within(PathSegment::AnyOf(i)));
+                    match &last {
+                        // with more input, this alternative
+                        // might still have matched
+                        Ok(_) => return last,
+                        Err(e) if e.is_incomplete() => return last,
+                        Err(_) => ctx.restore(mark),
                     }
                 }
                 last
             }
+            Pattern::If(..) | Pattern::Switch(..) => {
+                let (pat, case) = self.select(&ctx.env())?;
+                pat.parse_ctx(ctx)
+                    .map_err(|e| e.within(PathSegment::Case(case)))
+            }
             Pattern::AllOf(pats) => {
                 let mut ret = Vec::new();
-                for pat in pats {
-                    ret.push(pat.parse_ctx(ctx)?);
+                for (i, pat) in pats.iter().enumerate() {
+                    ret.push(
+                        pat.parse_ctx(ctx)
+                            .map_err(|e| e.within(PathSegment::AllOf(i)))?,
+                    );
+                }
+                Ok(ret.into())
+            }
+            Pattern::Record(fields) => {
+                let mut ret = Vec::new();
+                for (name, pat) in fields {
+                    let nspans = ctx.spans.as_ref().map(|spans| spans.len());
+                    let val = pat
+                        .parse_ctx(ctx)
+                        .map_err(|e| e.within(PathSegment::Field(name.clone())))?;
+                    ctx.name_span(nspans, name.as_str());
+                    ret.push((name.clone(), val));
                 }
                 Ok(ret.into())
             }
             Pattern::Store(pat, key) => {
                 let val = pat.parse_ctx(ctx)?;
-                ctx.scope_mut().set(*key, val.clone());
+                ctx.scope_mut().set(key, val.clone());
                 Ok(val)
             }
-            Pattern::Map(pat, f) => {
+            Pattern::Scoped(pat, exports) => {
+                ctx.push_stack();
+                let result = pat.parse_ctx(ctx).and_then(|val| {
+                    let mut exported = Vec::new();
+                    for var in exports {
+                        exported.push((var, ctx.scope().get_or_error(var)?.clone()));
+                    }
+                    Ok((val, exported))
+                });
+                ctx.pop_stack();
+                let (val, exported) = result?;
+                for (var, exported_val) in exported {
+                    ctx.scope_mut().set(var, exported_val);
+                }
+                Ok(val)
+            }
+            Pattern::Map(pat, f, _) => {
                 let val = pat.parse_ctx(ctx)?;
                 Ok(f(ctx.scope(), val)?)
             }
+            Pattern::Label(pat, name) => {
+                let nspans = ctx.spans.as_ref().map(|spans| spans.len());
+                let val = pat
+                    .parse_ctx(ctx)
+                    .map_err(|e| e.within(PathSegment::Label(name.clone())))?;
+                ctx.name_span(nspans, name.clone());
+                Ok(vec![name.clone(), val].into())
+            }
+        }
+    }
+
+    /// For If and Switch, picks the pattern to continue with,
+    /// along with the name of the case for error paths
+    pub(crate) fn select(&self, env: &Env) -> Result<(&Pattern, Data), ParseError> {
+        match self {
+            Pattern::If(cond, then_, else_) => {
+                if truthy(cond.eval(env)?)? {
+                    Ok((then_, "then".into()))
+                } else {
+                    Ok((else_, "else".into()))
+                }
+            }
+            Pattern::Switch(expr, cases, default) => {
+                let val = expr.eval(env)?;
+                for (key, pat) in cases {
+                    if *key == val {
+                        return Ok((pat, val));
+                    }
+                }
+                match default {
+                    Some(pat) => Ok((pat, "default".into())),
+                    None => Err(ParseError::custom("No matching case")
+                        .with_expected(cases.iter().map(|(key, _)| key.clone()).collect::<Vec<_>>())
+                        .with_actual(val)),
+                }
+            }
+            _ => panic!("select is only for If and Switch"),
         }
     }
 
@@ -158,45 +1015,60 @@ impl Pattern {
     /// returns a new Pattern mapped by adding the given value
     /// to the resulting value
     ///   - numeric types can be added to each other,
-    ///       with two integral types, the result is an intgral value
-    ///       otherwise, you get a Float value
+    ///     with two integral types, the result is an integral value
+    ///     (or an Overflow error, if it does not fit in an i128)
+    ///     otherwise, you get a Float value
     ///   - string types can be added to each other
-    ///       to create a concatenated string
+    ///     to create a concatenated string
+    ///
+    /// When rendering, the value is subtracted (or the suffix
+    /// stripped) again
+    #[allow(clippy::should_implement_trait)]
     pub fn add<D: Into<Data>>(self, rhs: D) -> Pattern {
         let rhs = rhs.into();
-        self.map(move |_, lhs| {
-            let rhs = rhs.clone();
-            match (lhs, rhs) {
-                (Data::Int(a), Data::Int(b)) => Ok((a + b).into()),
-                (Data::Float(a), Data::Float(b)) => Ok((a + b).into()),
-                (Data::Float(a), Data::Int(b)) => Ok((a + b as f64).into()),
-                (Data::Int(a), Data::Float(b)) => Ok((a as f64 + b).into()),
-                (Data::String(a), Data::String(b)) => Ok(format!("{}{}", a, b).into()),
-                (a, b) => err(format!("Could not add given values ({:?}, {:?})", a, b,)),
-            }
-        })
+        let inverse_rhs = rhs.clone();
+        self.bimap(
+            move |_, lhs| {
+                let rhs = rhs.clone();
+                match (lhs, rhs) {
+                    (Data::String(a), Data::String(b)) => Ok(format!("{}{}", a, b).into()),
+                    (a, b) => arith(a, b, "+", i128::checked_add, Some(|x, y| x + y)),
+                }
+            },
+            move |_, sum| match (sum, inverse_rhs.clone()) {
+                (Data::String(a), Data::String(b)) if a.ends_with(b.as_str()) => {
+                    Ok(a[..a.len() - b.len()].into())
+                }
+                (a @ Data::String(_), b) => Err(ParseError::new(
+                    ErrorKind::TypeMismatch,
+                    format!("Could not subtract given values ({:?}, {:?})", a, b),
+                )),
+                (a, b) => arith(a, b, "-", i128::checked_sub, Some(|x, y| x - y)),
+            },
+        )
     }
 
     /// if self -> X, this changes this pattern so that it returns
     /// [name, X] instead.
     /// Primarily for debugging purposes, when you want to see
-    /// what value comes from what pattern
+    /// what value comes from what pattern.
+    /// The name also shows up in the path of any ParseError
+    /// raised from within this pattern
     pub fn label<D: Into<Data>>(self, name: D) -> Pattern {
-        let name = name.into();
-        self.map(move |_, data| Ok(vec![name.clone(), data].into()))
+        Pattern::Label(self.into(), name.into())
     }
 
     /// convenience method that accepts a list of keys
     /// and returns a Pattern that when parsed will return a
     /// map of list of (name, value) pairs, where the names
-    /// are generated from Debug of the keys and value comes from
+    /// are the names of the variables and value comes from
     /// lookup up the Scope
-    pub fn to_map<K: Into<i64> + Debug>(self, keys: Vec<K>) -> Pattern {
+    pub fn to_map<K: Into<Var>>(self, keys: Vec<K>) -> Pattern {
         let pairs: Vec<_> = keys
             .into_iter()
             .map(|k| {
-                let s: Data = format!("{:?}", k).into();
-                let k: i64 = k.into();
+                let k: Var = k.into();
+                let s: Data = format!("{}", k).into();
                 (k, s)
             })
             .collect();
@@ -204,8 +1076,7 @@ impl Pattern {
             let mut ret = Vec::new();
             for (key, keystr) in pairs.clone() {
                 let val: Data = scope.get_or_error(key)?.clone();
-                let key: Data = keystr.into();
-                let pair: Data = vec![key, val].into();
+                let pair: Data = vec![keystr, val].into();
                 ret.push(pair);
             }
             Ok(ret.into())
@@ -213,41 +1084,133 @@ impl Pattern {
     }
 }
 
-fn uint(little_endian: bool, bytes: &[u8]) -> u64 {
-    let mut ret: u64 = 0;
+/// Decodes a string and trims off any trailing padding chars
+fn decode(
+    bytes: &[u8],
+    encoding: Encoding,
+    padding: &[u8],
+    lenient: bool,
+) -> Result<Data, ParseError> {
+    match encoding.decode(bytes) {
+        Ok(s) => {
+            let is_padding = |c: char| (c as u32) < 0x100 && padding.contains(&(c as u8));
+            Ok(s.trim_end_matches(is_padding).into())
+        }
+        Err(_) if lenient => {
+            let mut end = bytes.len();
+            while end > 0 && padding.contains(&bytes[end - 1]) {
+                end -= 1;
+            }
+            Ok(bytes[..end].into())
+        }
+        Err(error) => Err(error),
+    }
+}
+
+/// The result of enumerate or flags for a value with no name
+fn unknown_value(unknown: Unknown, value: Data) -> Result<Data, ParseError> {
+    match unknown {
+        Unknown::Error => Err(ParseError::new(
+            ErrorKind::Validation,
+            format!("{} has no name", value),
+        )
+        .with_actual(value)),
+        Unknown::Keep => Ok(value),
+        Unknown::Tag => Ok(Data::fseq(vec!["Unknown".into(), value])),
+    }
+}
+
+/// The inverse of unknown_value, accepting either form
+fn known_value(data: Data) -> Result<Data, ParseError> {
+    match &data {
+        Data::Seq(items) if items.len() == 2 && items[0] == "Unknown".into() => {
+            Ok(items[1].clone())
+        }
+        _ if data.i128().is_some() => Ok(data),
+        _ => Err(ParseError::custom(format!("Unknown name {}", data))),
+    }
+}
+
+/// Parses pat without consuming any input, or leaving any spans.
+/// Also returns where pat ended
+fn lookahead(ctx: &mut Context, pat: &Pattern) -> (Result<Data, ParseError>, usize) {
+    let mark = ctx.save();
+    let nspans = ctx.spans.as_ref().map(Vec::len);
+    let result = pat.parse_ctx(ctx);
+    let end = ctx.pos();
+    ctx.restore(mark);
+    if let (Some(spans), Some(n)) = (&mut ctx.spans, nspans) {
+        spans.truncate(n);
+    }
+    (result, end)
+}
+
+/// Number of whole bytes left before the end of the input (or limit)
+fn rest_len(ctx: &Context) -> usize {
+    (ctx.pos() + ctx.remaining()).saturating_sub(ctx.aligned_pos())
+}
+
+/// Evaluates an Expr that must result in a byte count or offset
+fn eval_offset(ctx: &Context, expr: &Expr, what: &str) -> Result<usize, ParseError> {
+    match expr.eval(&ctx.env())? {
+        Data::Int(i) if i >= 0 => Ok(i as usize),
+        x => Err(type_mismatch("usize", x, what)),
+    }
+}
+
+fn out_of_bounds(offset: usize, end: usize) -> ParseError {
+    ParseError::new(
+        ErrorKind::UnexpectedEof,
+        format!("Offset {} is past the end of the input ({})", offset, end),
+    )
+    .with_expected(offset as i64)
+    .with_actual(end as i64)
+}
+
+fn before_start(offset: usize, start: usize) -> ParseError {
+    ParseError::new(
+        ErrorKind::UnexpectedEof,
+        format!(
+            "Offset {} is before the start of the limit ({})",
+            offset, start
+        ),
+    )
+    .with_expected(offset as i64)
+    .with_actual(start as i64)
+}
+
+fn type_mismatch(expected: &str, actual: Data, what: &str) -> ParseError {
+    ParseError::new(
+        ErrorKind::TypeMismatch,
+        format!("Got non-{} for {} ({:?})", expected, what, actual),
+    )
+    .with_expected(expected)
+    .with_actual(actual)
+}
+
+fn uint(little_endian: bool, bytes: &[u8]) -> u128 {
+    let mut ret: u128 = 0;
     if little_endian {
         for byte in bytes.iter().rev() {
             ret <<= 8;
-            ret += (*byte) as u64;
+            ret += (*byte) as u128;
         }
     } else {
         for byte in bytes {
             ret <<= 8;
-            ret += (*byte) as u64;
+            ret += (*byte) as u128;
         }
     }
     ret
 }
 
-fn sint(little_endian: bool, bytes: &[u8]) -> i64 {
-    let mut bytes = bytes.to_vec();
-    let byte = if little_endian {
-        *bytes.last_mut().unwrap() as i8
-    } else {
-        bytes[0] as i8
-    };
-    let minus = if byte < 0 {
-        for byte in &mut bytes {
-            *byte = !*byte;
-        }
-        true
-    } else {
-        false
-    };
-    let ui = uint(little_endian, &bytes);
-    if minus {
-        -(ui.wrapping_add(1) as i64)
+fn sint(little_endian: bool, bytes: &[u8]) -> i128 {
+    let bits = bytes.len() * 8;
+    let ui = uint(little_endian, bytes);
+    if bits < 128 && ui >> (bits - 1) == 1 {
+        // sign extend
+        (ui | u128::MAX << bits) as i128
     } else {
-        ui as i64
+        ui as i128
     }
 }
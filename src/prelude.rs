@@ -1,6 +1,20 @@
+use crate::BitOrder;
+use crate::Checksum;
+use crate::Data;
+use crate::Encoding;
+use crate::Endian;
+use crate::ErrorKind;
 pub use crate::Expr;
+use crate::FieldVec;
+use crate::Fit;
+use crate::ParseError;
 pub use crate::Pattern;
 use crate::PatternVec;
+use crate::Repeat;
+use crate::StrLen;
+use crate::Var;
+use crate::Varint;
+use crate::MAX_REPEAT;
 
 pub fn magic(bytes: &[u8]) -> Pattern {
     Pattern::Exact(bytes.to_vec())
@@ -27,7 +41,7 @@ pub fn le_magic_u32(x: u32) -> Pattern {
 }
 
 pub fn le_magic_u64(x: u64) -> Pattern {
-    magic(&x.to_le_bytes()).mapval(x as i64)
+    magic(&x.to_le_bytes()).mapval(Data::fu64(x))
 }
 
 pub fn be_magic_u16(x: u16) -> Pattern {
@@ -39,7 +53,7 @@ pub fn be_magic_u32(x: u32) -> Pattern {
 }
 
 pub fn be_magic_u64(x: u64) -> Pattern {
-    magic(&x.to_be_bytes()).mapval(x as i64)
+    magic(&x.to_be_bytes()).mapval(Data::fu64(x))
 }
 
 pub const U8: Pattern = Pattern::U8;
@@ -51,6 +65,8 @@ pub const LE_U64: Pattern = Pattern::LeU64;
 pub const BE_U16: Pattern = Pattern::BeU16;
 pub const BE_U32: Pattern = Pattern::BeU32;
 pub const BE_U64: Pattern = Pattern::BeU64;
+pub const LE_U128: Pattern = Pattern::LeU128;
+pub const BE_U128: Pattern = Pattern::BeU128;
 
 pub const LE_I16: Pattern = Pattern::LeI16;
 pub const LE_I32: Pattern = Pattern::LeI32;
@@ -58,18 +74,274 @@ pub const LE_I64: Pattern = Pattern::LeI64;
 pub const BE_I16: Pattern = Pattern::BeI16;
 pub const BE_I32: Pattern = Pattern::BeI32;
 pub const BE_I64: Pattern = Pattern::BeI64;
+pub const LE_I128: Pattern = Pattern::LeI128;
+pub const BE_I128: Pattern = Pattern::BeI128;
 
 // By default, if no endianness is specified,
 // assume little endian
 pub const U16: Pattern = Pattern::LeU16;
 pub const U32: Pattern = Pattern::LeU32;
 pub const U64: Pattern = Pattern::LeU64;
+pub const U128: Pattern = Pattern::LeU128;
 pub const I16: Pattern = Pattern::LeI16;
 pub const I32: Pattern = Pattern::LeI32;
 pub const I64: Pattern = Pattern::LeI64;
+pub const I128: Pattern = Pattern::LeI128;
+
+// Endian-neutral, with the byte order set by an enclosing
+// with_endian, and little endian outside of any
+pub fn u16() -> Pattern {
+    Pattern::DynU16
+}
+pub fn u32() -> Pattern {
+    Pattern::DynU32
+}
+pub fn u64() -> Pattern {
+    Pattern::DynU64
+}
+pub fn u128() -> Pattern {
+    Pattern::DynU128
+}
+pub fn i16() -> Pattern {
+    Pattern::DynI16
+}
+pub fn i32() -> Pattern {
+    Pattern::DynI32
+}
+pub fn i64() -> Pattern {
+    Pattern::DynI64
+}
+pub fn i128() -> Pattern {
+    Pattern::DynI128
+}
+pub fn f32() -> Pattern {
+    Pattern::DynF32
+}
+pub fn f64() -> Pattern {
+    Pattern::DynF64
+}
+
+/// Results in what p would parse, without consuming any input,
+/// e.g. to look at a tag that the chosen record parses again
+pub fn peek(p: Pattern) -> Pattern {
+    Pattern::Peek(p.into())
+}
+
+/// Succeeds only if p fails, without consuming any input
+pub fn not(p: Pattern) -> Pattern {
+    Pattern::Not(p.into())
+}
+
+/// Succeeds only at the end of the input
+pub fn eof() -> Pattern {
+    Pattern::Eof
+}
+
+/// All remaining bytes
+pub fn rest() -> Pattern {
+    Pattern::Rest
+}
 
+/// Parses p with the byte order that endian evaluates to, which
+/// is "little" or "big" (e.g. Endian::Big, or a stored byte_order)
+pub fn with_endian<E: Into<Expr>>(endian: E, p: Pattern) -> Pattern {
+    Pattern::WithEndian(endian.into(), p.into())
+}
+
+/// A marker that gives the byte order of what follows, resulting
+/// in "little" or "big", e.g. byte_order(b"II", b"MM") for TIFF
+pub fn byte_order(little: &[u8], big: &[u8]) -> Pattern {
+    let marker = |bytes: &[u8], endian: Endian| {
+        let pattern = magic(bytes);
+        let bytes = Data::from(bytes);
+        pattern.bimap(
+            move |_, _| Ok(endian.into()),
+            move |_, data| match Endian::from_data(&data) {
+                Some(e) if e == endian => Ok(bytes.clone()),
+                _ => Err(ParseError::custom(format!(
+                    "Expected byte order {:?} but got {}",
+                    endian, data
+                ))),
+            },
+        )
+    };
+    any_of((marker(little, Endian::Little), marker(big, Endian::Big)))
+}
+
+pub const ULEB128: Pattern = Pattern::Varint(Varint::Uleb128);
+pub const SLEB128: Pattern = Pattern::Varint(Varint::Sleb128);
+pub const VLQ: Pattern = Pattern::Varint(Varint::Vlq);
+pub const GIT_VARINT: Pattern = Pattern::Varint(Varint::Git);
+
+// protobuf's varint is plain unsigned LEB128,
+// and its sint types add zigzag encoding on top
+pub const VARINT: Pattern = Pattern::Varint(Varint::Uleb128);
+pub const ZIGZAG: Pattern = Pattern::Varint(Varint::ZigZag);
+
+/// A NUL terminated UTF-8 string, consuming the NUL
 pub const CSTR: Pattern = Pattern::CStr;
 
+/// p may read at most the next size bytes,
+/// and whatever it leaves of them is skipped
+pub fn sized<E: Into<Expr>>(size: E, p: Pattern) -> Pattern {
+    Pattern::Sized(p.into(), size.into(), Fit::Skip)
+}
+
+/// Like sized, but it is an error if p does not use all size bytes
+pub fn sized_exact<E: Into<Expr>>(size: E, p: Pattern) -> Pattern {
+    Pattern::Sized(p.into(), size.into(), Fit::Exact)
+}
+
+/// Parses p at an absolute offset into the input,
+/// then continues where it left off, e.g. to follow a pointer
+pub fn at<E: Into<Expr>>(offset: E, p: Pattern) -> Pattern {
+    Pattern::At(p.into(), offset.into())
+}
+
+/// Skips n bytes, resulting in them (or in n, with
+/// Pattern::parse_reader, which does not read them)
+pub fn skip<E: Into<Expr>>(n: E) -> Pattern {
+    Pattern::Skip(n.into())
+}
+
+/// Continues parsing at an absolute offset into the input
+pub fn seek<E: Into<Expr>>(offset: E) -> Pattern {
+    Pattern::Seek(offset.into())
+}
+
+/// Stores the current offset into the scope, resulting in it,
+/// e.g. to give where a checksum range starts
+pub fn mark<K: Into<Var>>(key: K) -> Pattern {
+    seek(offset()).store(key)
+}
+
+/// The stored checksum of the bytes between the absolute offsets
+/// in range, e.g. for a PNG chunk whose type starts at "start",
+/// `checksum(Checksum::Crc32, (getvar("start"), offset()), BE_U32)`
+pub fn checksum<S, E>(algorithm: Checksum, range: (S, E), stored: Pattern) -> Pattern
+where
+    S: Into<Expr>,
+    E: Into<Expr>,
+{
+    Pattern::Checksum(algorithm, range.0.into(), range.1.into(), stored.into())
+}
+
+/// Unsigned integer of n (at most 64) bits, most significant bit first
+///
+/// # Panics
+///
+/// If n is 0 or more than 64
+pub fn bits(n: u32) -> Pattern {
+    assert!(0 < n && n <= 64, "bits must be between 1 and 64");
+    Pattern::Bits(n, BitOrder::Msb)
+}
+
+/// Like bits, but least significant bit first
+///
+/// # Panics
+///
+/// If n is 0 or more than 64
+pub fn bits_lsb(n: u32) -> Pattern {
+    assert!(0 < n && n <= 64, "bits must be between 1 and 64");
+    Pattern::Bits(n, BitOrder::Lsb)
+}
+
+/// A single bit flag, resulting in Int 1 or 0
+pub fn bool_bit() -> Pattern {
+    bits(1)
+}
+
+/// Splits the integer resulting from p into a Map of named fields
+/// of the given widths in bits, starting from the most significant
+/// end, e.g. bitfield(BE_U16, &[("flags", 3), ("len", 13)]). Any
+/// bits after the fields are left out (and rendered as zeros).
+/// If p is not a fixed width integer, the fields fill its value
+///
+/// # Panics
+///
+/// If the fields are wider in total than p, or than 64 bits
+pub fn bitfield(p: Pattern, fields: &[(&str, u32)]) -> Pattern {
+    let fields: Vec<(String, u32)> = fields
+        .iter()
+        .map(|(name, width)| (name.to_string(), *width))
+        .collect();
+    let total: u32 = fields.iter().map(|(_, width)| width).sum();
+    assert!(total <= 64, "bitfield must be at most 64 bits wide");
+    let int_width = p.bit_width().unwrap_or(total);
+    assert!(total <= int_width, "bitfield is wider than its integer");
+    let mask = |width: u32| u64::MAX.checked_shr(64 - width).unwrap_or(0);
+    let inverse_fields = fields.clone();
+    p.bimap(
+        move |_, data| {
+            // a negative value stands for its two's complement
+            let unsigned = data.i64().map(|i| i as u64 & mask(int_width));
+            let value = match data.u64().or(unsigned) {
+                Some(u) => u,
+                None => {
+                    return Err(ParseError::new(
+                        ErrorKind::TypeMismatch,
+                        "Expected int for bitfield",
+                    )
+                    .with_actual(data))
+                }
+            };
+            let mut shift = int_width;
+            let mut ret = Vec::new();
+            for (name, width) in &fields {
+                shift -= width;
+                let field = value.checked_shr(shift).unwrap_or(0) & mask(*width);
+                ret.push((name.clone(), Data::fu64(field)));
+            }
+            Ok(ret.into())
+        },
+        move |_, data| {
+            let mut value: u64 = 0;
+            for (name, width) in &inverse_fields {
+                let field = match data.get(name).and_then(Data::u128) {
+                    Some(u) if u & !(mask(*width) as u128) == 0 => u as u64,
+                    x => {
+                        return Err(ParseError::custom(format!(
+                            "Expected {} bit int for field {:?} but got {:?}",
+                            width, name, x
+                        )))
+                    }
+                };
+                value = value.checked_shl(*width).unwrap_or(0) | field;
+            }
+            Ok(Data::fu64(
+                value.checked_shl(int_width - total).unwrap_or(0),
+            ))
+        },
+    )
+}
+
+/// String preceded by its length in bytes, e.g. pascal_string(U8)
+pub fn pascal_string(len: Pattern) -> Pattern {
+    Pattern::Str(StrLen::Prefix(len.into()), Encoding::Utf8, vec![], false)
+}
+
+/// String in a field of n bytes, with any NUL padding
+/// trimmed off the end (see Pattern::padding to change that)
+pub fn fixed_string<E: Into<Expr>>(n: E) -> Pattern {
+    Pattern::Str(StrLen::Fixed(n.into()), Encoding::Utf8, vec![0], false)
+}
+
+/// String ending with the given terminator, which is not
+/// part of the result
+///
+/// # Panics
+///
+/// If the terminator is empty
+pub fn string_until(terminator: &[u8]) -> Pattern {
+    assert!(!terminator.is_empty(), "terminator must not be empty");
+    Pattern::Str(
+        StrLen::Until(terminator.to_vec()),
+        Encoding::Utf8,
+        vec![],
+        false,
+    )
+}
+
 pub fn any_of<PV: Into<PatternVec>>(pv: PV) -> Pattern {
     Pattern::AnyOf(pv.into().get())
 }
@@ -78,13 +350,97 @@ pub fn all_of<PV: Into<PatternVec>>(pv: PV) -> Pattern {
     Pattern::AllOf(pv.into().get())
 }
 
+/// Like all_of, but results in a Data::Map of the named fields
+pub fn record<F: Into<FieldVec>>(fields: F) -> Pattern {
+    Pattern::Record(fields.into().get())
+}
+
 pub fn array_of<E: Into<Expr>>(p: Pattern, e: E) -> Pattern {
     Pattern::Array(p.into(), e.into())
 }
 
+/// Parses then_ if cond evaluates to a non-zero int, else_ otherwise
+pub fn if_else<E: Into<Expr>>(cond: E, then_: Pattern, else_: Pattern) -> Pattern {
+    Pattern::If(cond.into(), then_.into(), else_.into())
+}
+
+/// Parses the pattern whose key equals the value of e,
+/// or default if none do (or fails, if there is no default)
+pub fn switch<E: Into<Expr>, D: Into<Data>>(
+    e: E,
+    cases: Vec<(D, Pattern)>,
+    default: Option<Pattern>,
+) -> Pattern {
+    Pattern::Switch(
+        e.into(),
+        cases.into_iter().map(|(k, p)| (k.into(), p)).collect(),
+        default.map(|p| p.into()),
+    )
+}
+
+/// Variables stored while parsing p are only visible within p
+pub fn scoped(p: Pattern) -> Pattern {
+    Pattern::Scoped(p.into(), vec![])
+}
+
+/// Like scoped, but the listed variables are copied out
+/// to the enclosing scope afterwards
+pub fn scoped_export<V: Into<Var>>(p: Pattern, exports: Vec<V>) -> Pattern {
+    Pattern::Scoped(p.into(), exports.into_iter().map(|v| v.into()).collect())
+}
+
+/// Parses p repeatedly, stopping after the first element
+/// for which cond evaluates to non-zero (e.g. a terminating record)
+pub fn repeat_until<E: Into<Expr>>(p: Pattern, cond: E) -> Pattern {
+    Pattern::Repeat(p.into(), Repeat::Until(cond.into()), MAX_REPEAT)
+}
+
+/// Parses p repeatedly, as long as cond evaluates to non-zero
+/// before each element
+pub fn repeat_while<E: Into<Expr>>(p: Pattern, cond: E) -> Pattern {
+    Pattern::Repeat(p.into(), Repeat::While(cond.into()), MAX_REPEAT)
+}
+
+/// Parses p as many times as it matches (possibly zero)
+pub fn many(p: Pattern) -> Pattern {
+    Pattern::Repeat(p.into(), Repeat::Many(0), MAX_REPEAT)
+}
+
+/// Parses p as many times as it matches, but at least once
+pub fn many1(p: Pattern) -> Pattern {
+    Pattern::Repeat(p.into(), Repeat::Many(1), MAX_REPEAT)
+}
+
+/// Parses p repeatedly until the end of the input
+pub fn repeat_eof(p: Pattern) -> Pattern {
+    Pattern::Repeat(p.into(), Repeat::Eof, MAX_REPEAT)
+}
+
+/// Like array_of, but each element gets its own scope,
+/// so that e.g. a length stored by one element does not
+/// leak into the next
+pub fn array_of_scoped<E: Into<Expr>>(p: Pattern, e: E) -> Pattern {
+    array_of(scoped(p), e)
+}
+
+/// An expression that always evaluates to the given value
+pub fn constant<D: Into<Data>>(d: D) -> Expr {
+    d.into().into()
+}
+
+/// An expression that evaluates to the current position in the input
+pub fn offset() -> Expr {
+    Expr::with_env(|env| Ok((env.offset as i64).into()))
+}
+
+/// An expression that evaluates to the length of the whole input
+pub fn input_len() -> Expr {
+    Expr::with_env(|env| Ok((env.len as i64).into()))
+}
+
 /// Convenience method -- returns the expression from
 /// retrieving a value from the scope
-pub fn getvar<K: Into<i64>>(key: K) -> Expr {
+pub fn getvar<K: Into<Var>>(key: K) -> Expr {
     let key = key.into();
-    Expr::new(move |scope| scope.get_or_error(key).map(|d| d.clone()))
+    Expr::new(move |scope| scope.get_or_error(&key).cloned())
 }
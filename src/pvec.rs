@@ -1,5 +1,35 @@
 use crate::Pattern;
 
+/// The (name, pattern) pairs of a record
+pub struct FieldVec(Vec<(String, Pattern)>);
+impl FieldVec {
+    pub fn get(self) -> Vec<(String, Pattern)> {
+        self.0
+    }
+}
+impl<S: Into<String>, P: Into<Pattern>> From<Vec<(S, P)>> for FieldVec {
+    fn from(v: Vec<(S, P)>) -> FieldVec {
+        FieldVec(v.into_iter().map(|(s, p)| (s.into(), p.into())).collect())
+    }
+}
+macro_rules! field_vec_from_tuple {
+    ($($S:ident $P:ident $i:tt),*) => {
+        impl<$($S: Into<String>, $P: Into<Pattern>),*> From<($(($S, $P),)*)> for FieldVec {
+            fn from(a: ($(($S, $P),)*)) -> FieldVec {
+                FieldVec(vec![$((a.$i.0.into(), a.$i.1.into())),*])
+            }
+        }
+    };
+}
+field_vec_from_tuple!(S1 P1 0);
+field_vec_from_tuple!(S1 P1 0, S2 P2 1);
+field_vec_from_tuple!(S1 P1 0, S2 P2 1, S3 P3 2);
+field_vec_from_tuple!(S1 P1 0, S2 P2 1, S3 P3 2, S4 P4 3);
+field_vec_from_tuple!(S1 P1 0, S2 P2 1, S3 P3 2, S4 P4 3, S5 P5 4);
+field_vec_from_tuple!(S1 P1 0, S2 P2 1, S3 P3 2, S4 P4 3, S5 P5 4, S6 P6 5);
+field_vec_from_tuple!(S1 P1 0, S2 P2 1, S3 P3 2, S4 P4 3, S5 P5 4, S6 P6 5, S7 P7 6);
+field_vec_from_tuple!(S1 P1 0, S2 P2 1, S3 P3 2, S4 P4 3, S5 P5 4, S6 P6 5, S7 P7 6, S8 P8 7);
+
 pub struct PatternVec(Vec<Pattern>);
 impl PatternVec {
     pub fn get(self) -> Vec<Pattern> {
@@ -7,6 +37,7 @@ impl PatternVec {
     }
 }
 impl From<Vec<Pattern>> for PatternVec {
+    #[allow(clippy::useless_conversion)]
     fn from(v: Vec<Pattern>) -> PatternVec {
         PatternVec(v.into())
     }
@@ -0,0 +1,290 @@
+use crate::context::Fill;
+use crate::Context;
+use crate::Data;
+use crate::ErrorKind;
+use crate::Mark;
+use crate::ParseError;
+use crate::PathSegment;
+use crate::Pattern;
+use std::convert::TryFrom;
+use std::io;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+
+/// The least number of bytes read from a stream at a time
+const CHUNK: usize = 64 * 1024;
+
+/// The most chunks kept in memory at once
+const CHUNKS: usize = 4;
+
+/// A seekable stream with the few most recently used chunks kept
+/// in memory, so that only jumping around (e.g. with Pattern::at)
+/// to somewhere new goes back to the stream
+pub(crate) struct Buffered<R> {
+    reader: R,
+
+    // the length of the stream, once it is known
+    len: Option<usize>,
+
+    // least recently used first
+    chunks: Vec<Chunk>,
+}
+
+struct Chunk {
+    start: usize, // offset in the stream of bytes[0]
+    bytes: Vec<u8>,
+}
+
+impl<R: Read + Seek> Buffered<R> {
+    pub(crate) fn new(reader: R) -> Buffered<R> {
+        Buffered {
+            reader,
+            len: None,
+            chunks: Vec::new(),
+        }
+    }
+    pub(crate) fn len(&mut self) -> Result<usize, ParseError> {
+        if let Some(len) = self.len {
+            return Ok(len);
+        }
+        let len = self.reader.seek(SeekFrom::End(0)).map_err(io_error)?;
+        let len = usize::try_from(len).map_err(|_| {
+            ParseError::new(ErrorKind::Io, "Stream is too long to address")
+                .with_actual(Data::fu64(len))
+        })?;
+        self.len = Some(len);
+        Ok(len)
+    }
+}
+
+impl<R: Read + Seek> Fill for Buffered<R> {
+    fn fill(&mut self, pos: usize, n: usize) -> io::Result<&[u8]> {
+        let found = self
+            .chunks
+            .iter()
+            .position(|chunk| chunk.start <= pos && pos + n <= chunk.start + chunk.bytes.len());
+        let chunk = match found {
+            Some(i) => self.chunks.remove(i),
+            None => {
+                let mut bytes = if self.chunks.len() == CHUNKS {
+                    self.chunks.remove(0).bytes
+                } else {
+                    Vec::new()
+                };
+                bytes.clear();
+                self.reader.seek(SeekFrom::Start(pos as u64))?;
+                let want = n.max(CHUNK) as u64;
+                self.reader.by_ref().take(want).read_to_end(&mut bytes)?;
+                if bytes.len() < n {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "stream ended before its original length",
+                    ));
+                }
+                Chunk { start: pos, bytes }
+            }
+        };
+        self.chunks.push(chunk);
+        let chunk = &self.chunks[self.chunks.len() - 1];
+        let offset = pos - chunk.start;
+        Ok(&chunk.bytes[offset..offset + n])
+    }
+}
+
+pub(crate) fn io_error(error: io::Error) -> ParseError {
+    ParseError::new(ErrorKind::Io, format!("{}", error))
+}
+
+/// The results of parsing a pattern repeatedly from a stream,
+/// until its end (see Pattern::iter_records)
+pub struct Records<'p, R> {
+    pattern: &'p Pattern,
+    reader: Buffered<R>,
+
+    // where the next record starts, if not at the start
+    mark: Option<Mark>,
+
+    index: usize,
+    done: bool,
+}
+
+impl<'p, R: Read + Seek> Records<'p, R> {
+    pub(crate) fn new(pattern: &'p Pattern, reader: R) -> Records<'p, R> {
+        Records {
+            pattern,
+            reader: Buffered::new(reader),
+            mark: None,
+            index: 0,
+            done: false,
+        }
+    }
+    fn next_record(&mut self) -> Option<Result<Data, ParseError>> {
+        let len = match self.reader.len() {
+            Ok(len) => len,
+            Err(error) => return Some(Err(error)),
+        };
+        let mut ctx = Context::from_reader(&mut self.reader, len);
+        if let Some(mark) = self.mark {
+            ctx.restore(mark);
+        }
+        if ctx.remaining() == 0 {
+            return None;
+        }
+        let start = ctx.save();
+        let index = self.index;
+        let result = match self.pattern.parse_ctx(&mut ctx) {
+            Ok(_) if ctx.save() == start => {
+                Err(ParseError::custom("Record matched no input before the end")
+                    .with_offset(ctx.pos()))
+            }
+            result => result,
+        };
+        self.mark = Some(ctx.save());
+        self.index += 1;
+        Some(result.map_err(|e| e.within(PathSegment::Array(index))))
+    }
+}
+
+impl<'p, R: Read + Seek> Iterator for Records<'p, R> {
+    type Item = Result<Data, ParseError>;
+
+    /// The next record, or the error that stops the iteration
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let item = self.next_record();
+        if let None | Some(Err(_)) = item {
+            self.done = true;
+        }
+        item
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CHUNK;
+    use crate::prelude::*;
+    use crate::Data;
+    use crate::ErrorKind;
+    use crate::PathSegment;
+    use std::cell::Cell;
+    use std::io;
+    use std::io::Cursor;
+    use std::io::Read;
+    use std::io::Seek;
+    use std::io::SeekFrom;
+    use std::rc::Rc;
+
+    /// A Cursor that counts the bytes read from it
+    struct Counting<'a> {
+        cursor: Cursor<&'a [u8]>,
+        count: Rc<Cell<usize>>,
+    }
+
+    impl Read for Counting<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.cursor.read(buf)?;
+            self.count.set(self.count.get() + n);
+            Ok(n)
+        }
+    }
+
+    impl Seek for Counting<'_> {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.cursor.seek(pos)
+        }
+    }
+
+    #[test]
+    fn parse_reader() {
+        let mut bytes = vec![0; 4 * CHUNK];
+        bytes[..4].copy_from_slice(b"\x03abc");
+        bytes[3 * CHUNK..3 * CHUNK + 2].copy_from_slice(&[0x34, 0x12]);
+        let parser = all_of((pascal_string(U8), at(3 * CHUNK as i64, LE_U16)));
+        let count = Rc::new(Cell::new(0));
+        let reader = Counting {
+            cursor: Cursor::new(&bytes),
+            count: count.clone(),
+        };
+        let data = parser.parse_reader(reader).unwrap();
+        assert_eq!(data, parser.parse(&bytes).unwrap());
+        assert_eq!(data, Data::fseq(vec!["abc".into(), Data::Int(0x1234)]));
+
+        // the start, and the chunk jumped to
+        assert_eq!(count.get(), 2 * CHUNK);
+
+        let error = parser.parse_reader(Cursor::new(b"\x03ab")).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::UnexpectedEof);
+        assert_eq!(error.offset, Some(1));
+    }
+
+    #[test]
+    fn jump_back_and_forth() {
+        let mut bytes = vec![0; 4 * CHUNK];
+        bytes[3 * CHUNK..3 * CHUNK + 4].copy_from_slice(&[1, 0, 2, 0]);
+        bytes[2 * CHUNK] = 3;
+        let jump = |offset: usize| all_of((U8, at(offset as i64, LE_U16)));
+        let parser = all_of((jump(3 * CHUNK), jump(3 * CHUNK + 2), jump(2 * CHUNK)));
+        let count = Rc::new(Cell::new(0));
+        let reader = Counting {
+            cursor: Cursor::new(&bytes),
+            count: count.clone(),
+        };
+        let data = parser.parse_reader(reader).unwrap();
+        assert_eq!(data, parser.parse(&bytes).unwrap());
+
+        // each chunk once, however often the parse comes back to it
+        assert_eq!(count.get(), 3 * CHUNK);
+    }
+
+    #[test]
+    fn skip_without_reading() {
+        let mut bytes = vec![0; 4 * CHUNK];
+        bytes[3 * CHUNK + 1] = 7;
+        let parser = all_of((U8, skip(3 * CHUNK as i64), U8));
+        let count = Rc::new(Cell::new(0));
+        let reader = Counting {
+            cursor: Cursor::new(&bytes),
+            count: count.clone(),
+        };
+        let data = parser.parse_reader(reader).unwrap();
+        let skipped = Data::Int(3 * CHUNK as i64);
+        assert_eq!(data, Data::fseq(vec![Data::Int(0), skipped, Data::Int(7)]));
+
+        // the start, and the chunk after the skipped bytes
+        assert_eq!(count.get(), 2 * CHUNK - 1);
+
+        let error = parser
+            .parse_reader(Cursor::new(&bytes[..CHUNK]))
+            .unwrap_err();
+        assert_eq!(error.kind, ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn iter_records() {
+        let parser = pascal_string(U8);
+        let records: Vec<_> = parser
+            .iter_records(Cursor::new(b"\x02hi\x00\x05there"))
+            .collect();
+        assert_eq!(
+            records,
+            vec![Ok("hi".into()), Ok("".into()), Ok("there".into())]
+        );
+
+        // stops at the first error
+        let mut records = parser.iter_records(Cursor::new(b"\x02hi\x05the"));
+        assert_eq!(records.next(), Some(Ok("hi".into())));
+        let error = records.next().unwrap().unwrap_err();
+        assert_eq!(error.kind, ErrorKind::UnexpectedEof);
+        assert_eq!(error.offset, Some(4));
+        assert_eq!(error.path, vec![PathSegment::Array(1)]);
+        assert_eq!(records.next(), None);
+
+        assert_eq!(parser.iter_records(Cursor::new(b"")).count(), 0);
+        let records: Vec<_> = all_of(()).iter_records(Cursor::new(b"x")).collect();
+        assert_eq!(records.len(), 1);
+        assert!(records[0].is_err());
+    }
+}
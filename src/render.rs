@@ -1,5 +1,14 @@
 /// For quickly rendering data into bytes
+use crate::context::RenderContext;
+use crate::Data;
 use crate::Endian;
+use crate::Expr;
+use crate::Fit;
+use crate::PathSegment;
+use crate::Pattern;
+use crate::RenderError;
+use crate::Repeat;
+use crate::StrLen;
 
 pub enum Render {}
 
@@ -228,10 +237,451 @@ fn rend(r: &Renderable, out: &mut Vec<u8>) {
     }
 }
 
+impl Pattern {
+    /// The inverse of Pattern::parse -- serializes Data of the shape
+    /// this pattern would produce back into bytes
+    pub fn render(&self, data: &Data) -> Result<Vec<u8>, RenderError> {
+        let mut ctx = RenderContext::new();
+        self.render_ctx(&mut ctx, data)?;
+        Ok(ctx.finish())
+    }
+    fn render_ctx(&self, ctx: &mut RenderContext, data: &Data) -> Result<(), RenderError> {
+        match self {
+            Pattern::Exact(bytes) => match data {
+                Data::Bytes(b) if b.as_slice() != bytes.as_slice() => Err(RenderError::new(
+                    format!("Expected {:?} but got {:?}", bytes, b),
+                )),
+                _ => {
+                    ctx.write(bytes);
+                    Ok(())
+                }
+            },
+            Pattern::U8 => write_int(ctx, data, 1, false, true),
+            Pattern::I8 => write_int(ctx, data, 1, true, true),
+            Pattern::LeU16 => write_int(ctx, data, 2, false, true),
+            Pattern::LeU32 => write_int(ctx, data, 4, false, true),
+            Pattern::LeU64 => write_int(ctx, data, 8, false, true),
+            Pattern::LeU128 => write_int(ctx, data, 16, false, true),
+            Pattern::BeU16 => write_int(ctx, data, 2, false, false),
+            Pattern::BeU32 => write_int(ctx, data, 4, false, false),
+            Pattern::BeU64 => write_int(ctx, data, 8, false, false),
+            Pattern::BeU128 => write_int(ctx, data, 16, false, false),
+            Pattern::LeI16 => write_int(ctx, data, 2, true, true),
+            Pattern::LeI32 => write_int(ctx, data, 4, true, true),
+            Pattern::LeI64 => write_int(ctx, data, 8, true, true),
+            Pattern::LeI128 => write_int(ctx, data, 16, true, true),
+            Pattern::BeI16 => write_int(ctx, data, 2, true, false),
+            Pattern::BeI32 => write_int(ctx, data, 4, true, false),
+            Pattern::BeI64 => write_int(ctx, data, 8, true, false),
+            Pattern::BeI128 => write_int(ctx, data, 16, true, false),
+            Pattern::LeF32 => {
+                ctx.write(&(float(data)? as f32).to_le_bytes());
+                Ok(())
+            }
+            Pattern::LeF64 => {
+                ctx.write(&float(data)?.to_le_bytes());
+                Ok(())
+            }
+            Pattern::BeF32 => {
+                ctx.write(&(float(data)? as f32).to_be_bytes());
+                Ok(())
+            }
+            Pattern::BeF64 => {
+                ctx.write(&float(data)?.to_be_bytes());
+                Ok(())
+            }
+            Pattern::DynU16
+            | Pattern::DynU32
+            | Pattern::DynU64
+            | Pattern::DynU128
+            | Pattern::DynI16
+            | Pattern::DynI32
+            | Pattern::DynI64
+            | Pattern::DynI128
+            | Pattern::DynF32
+            | Pattern::DynF64 => self.with_byte_order(ctx.endian).render_ctx(ctx, data),
+            Pattern::Varint(kind) => {
+                let bits = int_bits(data, 64, kind.signed())?;
+                ctx.write(&kind.encode(bits as u64));
+                Ok(())
+            }
+            Pattern::CStr => match data {
+                Data::String(s) if !s.contains('\0') => {
+                    ctx.write(s.as_bytes());
+                    ctx.write(&[0]);
+                    Ok(())
+                }
+                x => Err(RenderError::new(format!(
+                    "Expected string without NUL but got {:?}",
+                    x
+                ))),
+            },
+            Pattern::Str(len, encoding, padding, lenient) => {
+                let bytes = match data {
+                    Data::String(s) => encoding.encode(s)?,
+                    Data::Bytes(bytes) if *lenient => bytes.to_vec(),
+                    x => return Err(RenderError::new(format!("Expected string but got {:?}", x))),
+                };
+                match len {
+                    StrLen::Prefix(pat) => {
+                        pat.render_ctx(ctx, &Data::Int(bytes.len() as i64))?;
+                        ctx.write(&bytes);
+                    }
+                    StrLen::Fixed(expr) => {
+                        let n = eval_offset(ctx, expr, "string length")?;
+                        let fill = match padding.first() {
+                            Some(byte) => encoding.padding(*byte),
+                            None => vec![],
+                        };
+                        let fits = match n.checked_sub(bytes.len()) {
+                            Some(0) => true,
+                            Some(rest) => !fill.is_empty() && rest % fill.len() == 0,
+                            None => false,
+                        };
+                        if !fits {
+                            return Err(RenderError::new(format!(
+                                "String of {} bytes does not fit in {}",
+                                bytes.len(),
+                                n
+                            )));
+                        }
+                        ctx.write(&bytes);
+                        for _ in 0..(n - bytes.len()) / fill.len().max(1) {
+                            ctx.write(&fill);
+                        }
+                    }
+                    StrLen::Until(terminator) => {
+                        let contains_terminator = !terminator.is_empty()
+                            && (0..bytes.len())
+                                .step_by(encoding.unit())
+                                .any(|i| bytes[i..].starts_with(terminator));
+                        if contains_terminator {
+                            return Err(RenderError::new(format!(
+                                "String {:?} contains its terminator",
+                                data
+                            )));
+                        }
+                        ctx.write(&bytes);
+                        ctx.write(terminator);
+                    }
+                }
+                Ok(())
+            }
+            Pattern::Bits(n, order) => {
+                let bits = int_bits(data, *n, false)?;
+                ctx.write_bits(bits as u64, *n, *order);
+                Ok(())
+            }
+            Pattern::Array(pat, expr) => {
+                let len = eval_offset(ctx, expr, "array len")?;
+                let items = seq(data, len)?;
+                for (i, item) in items.iter().enumerate() {
+                    pat.render_ctx(ctx, item)
+                        .map_err(|e| e.within(PathSegment::Array(i)))?;
+                }
+                Ok(())
+            }
+            Pattern::Repeat(pat, mode, max) => {
+                let items = match data {
+                    Data::Seq(items) => items,
+                    x => {
+                        return Err(RenderError::new(format!(
+                            "Expected sequence but got {:?}",
+                            x
+                        )))
+                    }
+                };
+                let min = match mode {
+                    Repeat::Many(min) => *min,
+                    _ => 0,
+                };
+                if items.len() < min || items.len() > *max {
+                    return Err(RenderError::new(format!(
+                        "Expected between {} and {} elements but got {}",
+                        min,
+                        max,
+                        items.len()
+                    )));
+                }
+                for (i, item) in items.iter().enumerate() {
+                    pat.render_ctx(ctx, item)
+                        .map_err(|e| e.within(PathSegment::Array(i)))?;
+                }
+                Ok(())
+            }
+            Pattern::Sized(pat, expr, fit) => {
+                let size = eval_offset(ctx, expr, "size")?;
+                let start = ctx.pos();
+                pat.render_ctx(ctx, data)?;
+                let used = ctx.pos() - start;
+                match fit {
+                    _ if used > size => Err(RenderError::new(format!(
+                        "Rendered {} bytes, which does not fit in {}",
+                        used, size
+                    ))),
+                    Fit::Exact if used < size => Err(RenderError::new(format!(
+                        "Rendered {} bytes, but expected exactly {}",
+                        used, size
+                    ))),
+                    _ => {
+                        ctx.write(&vec![0; size - used]);
+                        Ok(())
+                    }
+                }
+            }
+            Pattern::At(..) => Err(RenderError::new(
+                "Patterns at absolute offsets cannot be rendered",
+            )),
+            Pattern::Skip(expr) => {
+                let n = eval_offset(ctx, expr, "skip")?;
+                match data {
+                    Data::Bytes(bytes) if bytes.len() == n => ctx.write(bytes),
+                    // anything else (e.g. padding left out of hand written Data)
+                    // is filled with zeros
+                    _ => ctx.write(&vec![0; n]),
+                }
+                Ok(())
+            }
+            Pattern::Seek(expr) => {
+                let offset = eval_offset(ctx, expr, "offset")?;
+                if offset < ctx.pos() {
+                    return Err(RenderError::new(format!(
+                        "Cannot seek back to {} after writing {} bytes",
+                        offset,
+                        ctx.pos()
+                    )));
+                }
+                ctx.write(&vec![0; offset - ctx.pos()]);
+                Ok(())
+            }
+            // the bytes looked at belong to whatever follows
+            Pattern::Peek(_) | Pattern::Not(_) | Pattern::Eof => Ok(()),
+            Pattern::Rest => match data {
+                Data::Bytes(bytes) => {
+                    ctx.write(bytes);
+                    Ok(())
+                }
+                _ => Err(RenderError::new(format!(
+                    "Expected bytes but got {:?}",
+                    data
+                ))),
+            },
+            Pattern::Validate(pat, _) => pat.render_ctx(ctx, data),
+            // whatever value is given is replaced with the right one
+            Pattern::Checksum(checksum, start, end, pat) => {
+                let start = eval_offset(ctx, start, "checksum start")?;
+                let end = eval_offset(ctx, end, "checksum end")?;
+                if start > end || end > ctx.pos() {
+                    return Err(RenderError::new(format!(
+                        "Cannot compute a checksum of bytes {}..{} after writing {} bytes",
+                        start,
+                        end,
+                        ctx.pos()
+                    )));
+                }
+                let computed = checksum.compute(&ctx.written()[start..end]);
+                pat.render_ctx(ctx, &Data::fu64(computed))
+            }
+            Pattern::WithEndian(expr, pat) => {
+                let endian = expr.eval(&ctx.env())?;
+                let endian = match Endian::from_data(&endian) {
+                    Some(endian) => endian,
+                    None => {
+                        return Err(RenderError::new(format!(
+                            "Got non-endian byte order ({:?})",
+                            endian
+                        )))
+                    }
+                };
+                let outer = std::mem::replace(&mut ctx.endian, endian);
+                let result = pat.render_ctx(ctx, data);
+                ctx.endian = outer;
+                result
+            }
+            Pattern::AnyOf(pats) => {
+                let mark = ctx.save();
+                let mut last = Err(RenderError::new("Empty 'any-of'"));
+                for (i, pat) in pats.iter().enumerate() {
+                    last = pat
+                        .render_ctx(ctx, data)
+                        .map_err(|e| e.within(PathSegment::AnyOf(i)));
+                    if last.is_ok() {
+                        return last;
+                    } else {
+                        ctx.restore(mark);
+                    }
+                }
+                last
+            }
+            Pattern::If(..) | Pattern::Switch(..) => {
+                let (pat, case) = self.select(&ctx.env())?;
+                pat.render_ctx(ctx, data)
+                    .map_err(|e| e.within(PathSegment::Case(case)))
+            }
+            Pattern::AllOf(pats) => {
+                let items = seq(data, pats.len())?;
+                for (i, (pat, item)) in pats.iter().zip(items.iter()).enumerate() {
+                    pat.render_ctx(ctx, item)
+                        .map_err(|e| e.within(PathSegment::AllOf(i)))?;
+                }
+                Ok(())
+            }
+            Pattern::Record(fields) => {
+                for (name, pat) in fields {
+                    let val = match data.get(name) {
+                        Some(val) => val,
+                        None => {
+                            return Err(RenderError::new(format!(
+                                "Expected map with field {:?} but got {:?}",
+                                name, data
+                            )))
+                        }
+                    };
+                    pat.render_ctx(ctx, val)
+                        .map_err(|e| e.within(PathSegment::Field(name.clone())))?;
+                }
+                Ok(())
+            }
+            Pattern::Store(pat, key) => {
+                pat.render_ctx(ctx, data)?;
+                ctx.scope_mut().set(key, data.clone());
+                Ok(())
+            }
+            Pattern::Scoped(pat, exports) => {
+                ctx.push_stack();
+                let result = pat.render_ctx(ctx, data).and_then(|()| {
+                    let mut exported = Vec::new();
+                    for var in exports {
+                        exported.push((var, ctx.scope().get_or_error(var)?.clone()));
+                    }
+                    Ok(exported)
+                });
+                ctx.pop_stack();
+                for (var, exported_val) in result? {
+                    ctx.scope_mut().set(var, exported_val);
+                }
+                Ok(())
+            }
+            Pattern::Map(pat, _, inverse) => match inverse {
+                Some(inverse) => {
+                    let val = inverse(ctx.scope(), data.clone())?;
+                    pat.render_ctx(ctx, &val)
+                }
+                None => match pat.fixed_bytes() {
+                    Some(bytes) => {
+                        ctx.write(&bytes);
+                        Ok(())
+                    }
+                    None => Err(RenderError::new(
+                        "Mapped pattern has no inverse and cannot be rendered",
+                    )),
+                },
+            },
+            Pattern::Label(pat, name) => {
+                let items = seq(data, 2)?;
+                if &items[0] != name {
+                    return Err(RenderError::new(format!(
+                        "Expected label {} but got {}",
+                        name, items[0]
+                    )));
+                }
+                pat.render_ctx(ctx, &items[1])
+                    .map_err(|e| e.within(PathSegment::Label(name.clone())))
+            }
+        }
+    }
+
+    /// If this pattern always matches the same bytes regardless of
+    /// the Data it produces (e.g. magics), returns those bytes.
+    /// This is what allows mapped magics to be rendered without
+    /// an inverse
+    fn fixed_bytes(&self) -> Option<Vec<u8>> {
+        match self {
+            Pattern::Exact(bytes) => Some(bytes.clone()),
+            Pattern::AllOf(pats) => {
+                let mut ret = Vec::new();
+                for pat in pats {
+                    ret.extend(pat.fixed_bytes()?);
+                }
+                Some(ret)
+            }
+            Pattern::Map(pat, _, _) | Pattern::Label(pat, _) => pat.fixed_bytes(),
+            _ => None,
+        }
+    }
+}
+
+fn eval_offset(ctx: &RenderContext, expr: &Expr, what: &str) -> Result<usize, RenderError> {
+    match expr.eval(&ctx.env())? {
+        Data::Int(i) if i >= 0 => Ok(i as usize),
+        x => Err(RenderError::new(format!(
+            "Got non-int or negative {} ({:?})",
+            what, x
+        ))),
+    }
+}
+
+fn write_int(
+    ctx: &mut RenderContext,
+    data: &Data,
+    size: usize,
+    signed: bool,
+    little_endian: bool,
+) -> Result<(), RenderError> {
+    let bits = int_bits(data, size as u32 * 8, signed)?;
+    let mut bytes = bits.to_le_bytes()[..size].to_vec();
+    if !little_endian {
+        bytes.reverse();
+    }
+    ctx.write(&bytes);
+    Ok(())
+}
+
+/// The two's complement of an integer that has to fit in the given
+/// number of bits, with only those lowest bits being meaningful
+fn int_bits(data: &Data, bits: u32, signed: bool) -> Result<u128, RenderError> {
+    let fits = match (data.i128(), data.u128()) {
+        (None, None) => return Err(RenderError::new(format!("Expected int but got {:?}", data))),
+        (Some(i), _) if signed => {
+            let half = 1i128 << (bits - 1);
+            bits == 128 || (-half <= i && i < half)
+        }
+        (_, Some(u)) if !signed => u.checked_shr(bits).unwrap_or(0) == 0,
+        // negative for an unsigned type, or too big for a signed one
+        _ => false,
+    };
+    match fits {
+        true if signed => Ok(data.i128().unwrap() as u128),
+        true => Ok(data.u128().unwrap()),
+        false => Err(RenderError::new(format!(
+            "Value {} does not fit in {}{}",
+            data,
+            if signed { 'i' } else { 'u' },
+            bits
+        ))),
+    }
+}
+
+fn float(data: &Data) -> Result<f64, RenderError> {
+    crate::expr::float(data)
+        .ok_or_else(|| RenderError::new(format!("Expected float but got {:?}", data)))
+}
+
+fn seq(data: &Data, len: usize) -> Result<&Vec<Data>, RenderError> {
+    match data {
+        Data::Seq(items) if items.len() == len => Ok(items),
+        x => Err(RenderError::new(format!(
+            "Expected sequence of length {} but got {:?}",
+            len, x
+        ))),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::render;
     use super::Render;
+    use crate::Data;
 
     #[test]
     fn simple() {
@@ -246,4 +696,107 @@ mod tests {
             &[0xBB, 0xAA, 0, 0, 255, 248],
         );
     }
+
+    #[test]
+    fn floats() {
+        use crate::Pattern;
+
+        // any kind of number will do, however big
+        for (data, x) in [
+            (Data::Float(2.5), 2.5),
+            (Data::Int(-2), -2.0),
+            (Data::fu64(1 << 63), 2f64.powi(63)),
+            (Data::fi128(-1 << 100), -(2f64.powi(100))),
+            (Data::fu128(1 << 127), 2f64.powi(127)),
+        ] {
+            assert_eq!(
+                Pattern::LeF32.render(&data).unwrap(),
+                (x as f32).to_le_bytes()
+            );
+            assert_eq!(Pattern::BeF64.render(&data).unwrap(), x.to_be_bytes());
+        }
+        assert!(Pattern::LeF64.render(&"2".into()).is_err());
+    }
+
+    #[test]
+    fn roundtrip() {
+        let bytes = render((
+            1234u32,
+            1u32,
+            (Render::be_i16(-8), 777u64, 888u64),
+            (b'h', b'i', 0u8),
+        ));
+        let parser = {
+            use crate::prelude::*;
+            all_of((
+                le_magic_u32(1234),
+                U32.add(1).store(7),
+                BE_I16.label("x"),
+                array_of(LE_U64, getvar(7)),
+                CSTR,
+            ))
+        };
+
+        let data = parser.parse(&bytes).unwrap();
+        assert_eq!(parser.render(&data).unwrap(), bytes);
+
+        // tweak a field and write it back
+        let mut items = data.seq().unwrap().to_vec();
+        items[4] = "hey".into();
+        let data: Data = items.into();
+        assert_eq!(
+            parser.render(&data).unwrap(),
+            render((
+                1234u32,
+                1u32,
+                (Render::be_i16(-8), 777u64, 888u64),
+                (b'h', b'e', b'y', 0u8),
+            ))
+        );
+    }
+
+    #[test]
+    fn render_errors() {
+        use crate::prelude::*;
+        use crate::ErrorKind;
+        use crate::ParseError;
+        use crate::PathSegment;
+
+        let parser = all_of((U8, U16.label("width")));
+        let error = parser
+            .render(&Data::fseq(vec![
+                Data::Int(1),
+                vec!["width".into(), Data::Int(70000)].into(),
+            ]))
+            .unwrap_err();
+        assert_eq!(
+            error.path,
+            vec![PathSegment::AllOf(1), PathSegment::Label("width".into())]
+        );
+
+        let parser = U8.map(|_, d| Ok(d));
+        assert!(parser.render(&Data::Int(1)).is_err());
+
+        // errors from parsing machinery keep their kind and path
+        let parser = all_of((U8, array_of(U8, getvar("n"))));
+        let data = Data::fseq(vec![Data::Int(1), Data::fseq(vec![])]);
+        let error = parser.render(&data).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::UnknownKey);
+        assert_eq!(error.path, vec![PathSegment::AllOf(1)]);
+        let parser = U8.bimap(
+            |_, d| Ok(d),
+            |_, d| {
+                Err(ParseError::custom("inverse")
+                    .with_actual(d)
+                    .within(PathSegment::Array(2)))
+            },
+        );
+        let data = Data::fseq(vec!["x".into(), Data::Int(1)]);
+        let error = parser.label("x").render(&data).unwrap_err();
+        assert_eq!(
+            error.path,
+            vec![PathSegment::Label("x".into()), PathSegment::Array(2)]
+        );
+        assert_eq!(error.kind, ErrorKind::Custom);
+    }
 }
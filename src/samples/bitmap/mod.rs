@@ -1,5 +1,8 @@
 //! BMP file parser
+use crate::ParseError;
 use crate::Pattern;
+use crate::Unknown;
+use crate::Var;
 
 #[derive(Debug)]
 pub enum Key {
@@ -8,19 +11,19 @@ pub enum Key {
     DibHeaderSize,
     WidthInPixels,
     HeightInPixels,
+    BitsPerPixel,
 }
 
-impl From<Key> for i64 {
-    fn from(k: Key) -> i64 {
-        k as i64
+impl From<Key> for Var {
+    fn from(k: Key) -> Var {
+        format!("{:?}", k).into()
     }
 }
 
-// TODO
-// pub fn pattern() -> Pattern {
-//     use crate::prelude::*;
-//     file_header()
-// }
+pub fn pattern() -> Pattern {
+    use crate::prelude::*;
+    all_of((file_header(), dib_header()))
+}
 
 pub fn file_header() -> Pattern {
     use crate::prelude::*;
@@ -37,13 +40,56 @@ pub fn file_header() -> Pattern {
 pub fn dib_header() -> Pattern {
     use crate::prelude::*;
 
-    all_of(vec![
+    all_of((
         U32.store(Key::DibHeaderSize).label("dib-header-size"),
+        // the size counts itself, and later versions of the
+        // header append fields that are skipped here
+        sized(getvar(Key::DibHeaderSize) - 4, dib_fields()),
+    ))
+    // with the fields next to the size, as one flat Seq
+    .bimap(
+        |_, data| {
+            let parts = data.seq().unwrap();
+            let mut flat = vec![parts[0].clone()];
+            flat.extend(parts[1].seq().unwrap().iter().cloned());
+            Ok(flat.into())
+        },
+        |_, data| match data.seq() {
+            Some(flat) if !flat.is_empty() => {
+                Ok(vec![flat[0].clone(), flat[1..].to_vec().into()].into())
+            }
+            _ => Err(ParseError::custom(format!(
+                "Expected DIB header fields but got {:?}",
+                data
+            ))),
+        },
+    )
+}
+
+fn dib_fields() -> Pattern {
+    use crate::prelude::*;
+
+    all_of(vec![
         U32.store(Key::WidthInPixels).label("width-in-pixels"),
         U32.store(Key::HeightInPixels).label("height-in-pixels"),
         U16.label("color-planes"),
-        U16.label("bits-per-pixel"),
-        U32.label("compression-method"),
+        U16.store(Key::BitsPerPixel).label("bits-per-pixel"),
+        U32.enumerate(
+            vec![
+                (0, "BI_RGB"),
+                (1, "BI_RLE8"),
+                (2, "BI_RLE4"),
+                (3, "BI_BITFIELDS"),
+                (4, "BI_JPEG"),
+                (5, "BI_PNG"),
+                (6, "BI_ALPHABITFIELDS"),
+                (11, "BI_CMYK"),
+                (12, "BI_CMYKRLE8"),
+                (13, "BI_CMYKRLE4"),
+            ],
+            Unknown::Keep,
+        )
+        .label("compression-method"),
         U32.label("raw-image-size"),
         U32.label("horizontal-resolution"),
         U32.label("vertical-resolution"),
@@ -52,11 +98,24 @@ pub fn dib_header() -> Pattern {
     ])
 }
 
+/// The rows of the pixel array, each padded to a multiple of 4 bytes.
+/// Must come after the headers, which say where to find it
+pub fn pixel_rows() -> Pattern {
+    use crate::prelude::*;
+
+    let row_size = (getvar(Key::WidthInPixels) * getvar(Key::BitsPerPixel) + 31) / 32 * 4;
+    at(
+        getvar(Key::PixelOffset),
+        array_of(array_of(U8, row_size), getvar(Key::HeightInPixels)),
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::Data;
 
+    #[allow(clippy::redundant_static_lifetimes)]
     pub const BMP_BYTES: &'static [u8] = include_bytes!("TRU256.BMP");
 
     #[test]
@@ -96,4 +155,64 @@ mod tests {
             ])
         );
     }
+
+    #[test]
+    pub fn dib_header_fields_with_sample() {
+        let pat = dib_header();
+        let data = pat.parse(&BMP_BYTES[14..]).unwrap();
+        let fields = data.seq().unwrap();
+        assert_eq!(fields.len(), 11);
+        assert_eq!(
+            fields[0],
+            Data::fseq(vec!["dib-header-size".into(), Data::Int(40)])
+        );
+        assert_eq!(
+            fields[10],
+            Data::fseq(vec!["ignored-important-color-count".into(), Data::Int(0)])
+        );
+        assert_eq!(pat.render(&data).unwrap(), &BMP_BYTES[14..54]);
+    }
+
+    #[test]
+    pub fn compression_method() {
+        let pat = dib_header();
+        let mut bytes = BMP_BYTES[14..54].to_vec();
+        let method = |bytes: &[u8]| pat.parse(bytes).unwrap().seq().unwrap()[5].clone();
+        assert_eq!(
+            method(&bytes),
+            Data::fseq(vec!["compression-method".into(), "BI_RGB".into()])
+        );
+
+        bytes[16] = 12;
+        assert_eq!(
+            method(&bytes),
+            Data::fseq(vec!["compression-method".into(), "BI_CMYKRLE8".into()])
+        );
+        let data = pat.parse(&bytes).unwrap();
+        assert_eq!(pat.render(&data).unwrap(), bytes);
+
+        // values without a name are kept as they are
+        bytes[16] = 9;
+        let data = pat.parse(&bytes).unwrap();
+        assert_eq!(
+            method(&bytes),
+            Data::fseq(vec!["compression-method".into(), Data::Int(9)])
+        );
+        assert_eq!(pat.render(&data).unwrap(), bytes);
+    }
+
+    #[test]
+    pub fn pixel_rows_with_sample() {
+        let pat = crate::prelude::all_of((file_header(), dib_header(), pixel_rows()));
+        let data = pat.parse(BMP_BYTES).unwrap();
+        let rows = data.seq().unwrap()[2].seq().unwrap();
+        assert_eq!(rows.len(), 64);
+        assert_eq!(rows[0].seq().unwrap().len(), 256 * 3);
+
+        let fields = data.seq().unwrap()[1].seq().unwrap().clone();
+        assert_eq!(
+            fields[5],
+            Data::fseq(vec!["compression-method".into(), "BI_RGB".into()])
+        );
+    }
 }
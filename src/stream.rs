@@ -0,0 +1,109 @@
+use crate::Data;
+use crate::ParseError;
+use crate::Pattern;
+
+/// Parses a pattern again and again from input that arrives
+/// a piece at a time, such as messages framed on a TCP stream.
+/// Input is added with push, and each complete value taken
+/// with next, which drops the bytes it used
+pub struct StreamParser<'p> {
+    pattern: &'p Pattern,
+    buf: Vec<u8>,
+}
+
+impl<'p> StreamParser<'p> {
+    pub fn new(pattern: &'p Pattern) -> StreamParser<'p> {
+        StreamParser {
+            pattern,
+            buf: Vec::new(),
+        }
+    }
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buf.extend(bytes);
+    }
+
+    /// The next value, or None if the input so far ends before it
+    /// does. Offsets in errors are from the start of what is buffered
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Result<Option<Data>, ParseError> {
+        match self.pattern.parse_partial(&self.buf) {
+            Ok((_, 0)) => Err(ParseError::custom("Value matched no input")),
+            Ok((data, used)) => {
+                self.buf.drain(..used);
+                Ok(Some(data))
+            }
+            Err(e) if e.is_incomplete() => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Input that has been pushed but not yet used
+    pub fn buffered(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::StreamParser;
+    use crate::prelude::*;
+    use crate::Data;
+    use crate::ErrorKind;
+
+    #[test]
+    fn parse_partial() {
+        let parser = all_of((BE_U16.store("len"), array_of(U8, getvar("len"))));
+        let error = parser.parse_partial(b"\x00\x03ab").unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Incomplete { needed: Some(1) });
+        assert_eq!(error.offset, Some(4));
+        let (_, used) = parser.parse_partial(b"\x00\x01a\x00").unwrap();
+        assert_eq!(used, 3);
+
+        // a size limit is a real end
+        let error = sized(2, LE_U32)
+            .parse_partial(b"\x00\x00\x00\x00")
+            .unwrap_err();
+        assert_eq!(error.kind, ErrorKind::UnexpectedEof);
+        // even where it ends along with the input so far
+        let error = sized(2, LE_U32).parse_partial(b"\x00\x00").unwrap_err();
+        assert_eq!(error.kind, ErrorKind::UnexpectedEof);
+        assert!(sized(8, LE_U32)
+            .parse_partial(b"\x00")
+            .unwrap_err()
+            .is_incomplete());
+
+        // an alternative that might match with more input is not skipped
+        let parser = any_of((magic(b"ab"), U8));
+        assert!(parser.parse_partial(b"a").unwrap_err().is_incomplete());
+        assert_eq!(parser.parse_partial(b"x").unwrap(), (Data::Int(0x78), 1));
+
+        let error = many(U8).parse_partial(b"ab").unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Incomplete { needed: Some(1) });
+        let error = repeat_eof(U8).parse_partial(b"ab").unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Incomplete { needed: None });
+        assert!(CSTR.parse_partial(b"ab").unwrap_err().is_incomplete());
+        assert!(at(10, U8).parse_partial(b"ab").unwrap_err().is_incomplete());
+    }
+
+    #[test]
+    fn stream_parser() {
+        let parser = pascal_string(U8);
+        let mut stream = StreamParser::new(&parser);
+        assert_eq!(stream.next(), Ok(None));
+        stream.push(b"\x02h");
+        assert_eq!(stream.next(), Ok(None));
+        stream.push(b"i\x03a");
+        assert_eq!(stream.next(), Ok(Some("hi".into())));
+        assert_eq!(stream.next(), Ok(None));
+        assert_eq!(stream.buffered(), b"\x03a");
+        stream.push(b"bc\x00");
+        assert_eq!(stream.next(), Ok(Some("abc".into())));
+        assert_eq!(stream.next(), Ok(Some("".into())));
+        assert_eq!(stream.next(), Ok(None));
+
+        let parser = magic(b"ab");
+        let mut stream = StreamParser::new(&parser);
+        stream.push(b"ax");
+        assert_eq!(stream.next().unwrap_err().kind, ErrorKind::MagicMismatch);
+    }
+}
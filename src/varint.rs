@@ -0,0 +1,202 @@
+use crate::Context;
+use crate::Data;
+use crate::ErrorKind;
+use crate::ParseError;
+
+/// Variable length integer encodings, in which the high bit
+/// of each byte says whether another byte follows
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Varint {
+    Uleb128, // little endian groups of 7 bits (DWARF, WebAssembly, protobuf)
+    Sleb128, // like Uleb128, sign extended from the last group
+    ZigZag,  // Uleb128 of a zigzag encoded signed value (protobuf sint)
+    Vlq,     // big endian groups of 7 bits (MIDI, MP4)
+    Git,     // like Vlq, but adding 1 per continuation (git pack offsets)
+}
+
+/// Enough bytes for any 64 bit value, in any of the encodings
+const MAX_BYTES: usize = 10;
+
+impl Varint {
+    /// Whether this encoding is for signed integers
+    pub(crate) fn signed(self) -> bool {
+        matches!(self, Varint::Sleb128 | Varint::ZigZag)
+    }
+    pub(crate) fn read(self, ctx: &mut Context) -> Result<Data, ParseError> {
+        let start = ctx.pos();
+        let mut value: u64 = 0;
+        let mut shift = 0;
+        for i in 0..MAX_BYTES {
+            let byte = ctx.read(1)?[0];
+            let bits = (byte & 0x7F) as u64;
+            let last = byte & 0x80 == 0;
+            match self {
+                Varint::Uleb128 | Varint::ZigZag | Varint::Sleb128 => {
+                    // the 10th byte holds only the 64th bit, and for Sleb128
+                    // the rest of it has to be the sign extension of that bit
+                    let fits = match (i, self) {
+                        (9, Varint::Sleb128) => bits == 0 || bits == 0x7F,
+                        (9, _) => bits <= 1,
+                        _ => true,
+                    };
+                    if !fits {
+                        return Err(overflow(start));
+                    }
+                    value |= bits << shift;
+                    shift += 7;
+                }
+                Varint::Vlq | Varint::Git => {
+                    if value >> 57 != 0 {
+                        return Err(overflow(start));
+                    }
+                    value = value << 7 | bits;
+                }
+            }
+            if last {
+                return Ok(match self {
+                    Varint::Sleb128 if shift < 64 && bits & 0x40 != 0 => {
+                        Data::Int((value | u64::MAX << shift) as i64)
+                    }
+                    Varint::Sleb128 => Data::Int(value as i64),
+                    Varint::ZigZag => Data::Int((value >> 1) as i64 ^ -((value & 1) as i64)),
+                    _ => Data::fu64(value),
+                });
+            }
+            if let Varint::Git = self {
+                value = value.checked_add(1).ok_or_else(|| overflow(start))?;
+            }
+        }
+        Err(overflow(start))
+    }
+
+    /// The inverse of read, with signed values
+    /// given as their 64 bit two's complement
+    pub(crate) fn encode(self, value: u64) -> Vec<u8> {
+        let mut out = Vec::new();
+        match self {
+            Varint::Uleb128 | Varint::ZigZag => {
+                let mut value = match self {
+                    Varint::ZigZag => (value << 1) ^ ((value as i64 >> 63) as u64),
+                    _ => value,
+                };
+                loop {
+                    let bits = (value & 0x7F) as u8;
+                    value >>= 7;
+                    if value == 0 {
+                        out.push(bits);
+                        break;
+                    }
+                    out.push(bits | 0x80);
+                }
+            }
+            Varint::Sleb128 => {
+                let mut value = value as i64;
+                loop {
+                    let bits = (value & 0x7F) as u8;
+                    value >>= 7;
+                    let sign = bits & 0x40 != 0;
+                    if (value == 0 && !sign) || (value == -1 && sign) {
+                        out.push(bits);
+                        break;
+                    }
+                    out.push(bits | 0x80);
+                }
+            }
+            Varint::Vlq | Varint::Git => {
+                // built from the last byte backwards
+                let mut value = value;
+                out.push((value & 0x7F) as u8);
+                value >>= 7;
+                while value != 0 {
+                    if let Varint::Git = self {
+                        value -= 1;
+                    }
+                    out.push((value & 0x7F) as u8 | 0x80);
+                    value >>= 7;
+                }
+                out.reverse();
+            }
+        }
+        out
+    }
+}
+
+fn overflow(offset: usize) -> ParseError {
+    ParseError::new(ErrorKind::Overflow, "Varint does not fit in 64 bits").with_offset(offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Varint;
+    use crate::prelude::*;
+    use crate::Data;
+    use crate::ErrorKind;
+
+    #[test]
+    fn known_encodings() {
+        let cases: &[(Varint, i128, &[u8])] = &[
+            (Varint::Uleb128, 0, &[0x00]),
+            (Varint::Uleb128, 624485, &[0xE5, 0x8E, 0x26]),
+            (
+                Varint::Uleb128,
+                u64::MAX as i128,
+                &[0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x01],
+            ),
+            (Varint::Sleb128, -123456, &[0xC0, 0xBB, 0x78]),
+            (Varint::Sleb128, 63, &[0x3F]),
+            (Varint::Sleb128, 64, &[0xC0, 0x00]),
+            (
+                Varint::Sleb128,
+                i64::MIN as i128,
+                &[0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x7F],
+            ),
+            (Varint::ZigZag, -1, &[0x01]),
+            (Varint::ZigZag, 1, &[0x02]),
+            (Varint::ZigZag, -150, &[0xAB, 0x02]),
+            (Varint::Vlq, 0x7F, &[0x7F]),
+            (Varint::Vlq, 0x80, &[0x81, 0x00]),
+            (Varint::Vlq, 0x0FFFFFFF, &[0xFF, 0xFF, 0xFF, 0x7F]),
+            (Varint::Git, 127, &[0x7F]),
+            (Varint::Git, 128, &[0x80, 0x00]),
+            (Varint::Git, 16511, &[0xFF, 0x7F]),
+            (Varint::Git, 16512, &[0x80, 0x80, 0x00]),
+        ];
+        for (kind, value, bytes) in cases {
+            let pattern = Pattern::Varint(*kind);
+            assert_eq!(
+                pattern.parse(bytes).unwrap(),
+                Data::fi128(*value),
+                "{:?}",
+                kind
+            );
+            assert_eq!(
+                pattern.render(&Data::fi128(*value)).unwrap(),
+                *bytes,
+                "{:?}",
+                kind
+            );
+        }
+    }
+
+    #[test]
+    fn overflow() {
+        let too_long = [0x80; 11];
+        for kind in &[
+            Varint::Uleb128,
+            Varint::Sleb128,
+            Varint::ZigZag,
+            Varint::Vlq,
+            Varint::Git,
+        ] {
+            let error = Pattern::Varint(*kind).parse(&too_long).unwrap_err();
+            assert_eq!(error.kind, ErrorKind::Overflow, "{:?}", kind);
+            assert_eq!(error.offset, Some(0));
+        }
+        let error = ULEB128
+            .parse(&[0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x02])
+            .unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Overflow);
+        let error = SLEB128.parse(&[0x80, 0x80]).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::UnexpectedEof);
+    }
+}